@@ -1,3 +1,5 @@
+use crate::savestate::savestate_fields;
+
 #[derive(Debug, Default)]
 pub struct Envelope{
     pub initial_vol: u8,
@@ -43,4 +45,6 @@ impl Envelope {
         self.counter = self.period;
         self.enabled = true;
     }
-}
\ No newline at end of file
+}
+
+savestate_fields!(Envelope {initial_vol, current_vol, sweep_increase, period, enabled, counter});
\ No newline at end of file
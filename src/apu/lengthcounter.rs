@@ -1,3 +1,5 @@
+use crate::savestate::savestate_fields;
+
 #[derive(Debug)]
 pub struct LengthCounter {
     pub enabled: bool,
@@ -39,4 +41,6 @@ impl LengthCounter {
             self.counter = self.max_length - (!length_next && self.enabled) as u16;
         }
     }
-}
\ No newline at end of file
+}
+
+savestate_fields!(LengthCounter {enabled, max_length, counter});
\ No newline at end of file
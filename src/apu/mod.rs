@@ -3,11 +3,21 @@ pub mod wave;
 pub mod noise;
 pub mod envelope;
 pub mod lengthcounter;
+pub mod vgm;
 
 
 use self::square::Square;
 use self::wave::Wave;
 use self::noise::Noise;
+use self::vgm::VgmLogger;
+
+use std::io;
+use std::path::PathBuf;
+use crate::savestate::savestate_fields;
+
+pub const SAMPLE_RATE: f64 = 22_050_f64;
+pub const CPU_FREQ: f64 = 4_194_304_f64;
+const CART_TONE_FREQ: f64 = 2_205_f64;
 
 #[derive(Debug)]
 pub struct Apu{
@@ -26,14 +36,26 @@ pub struct Apu{
 
     pub div_bit: bool,
 
+    pub cycles: u64, // tstates since power on, timestamps vgm writes
     pub sample_counter: f64,
+    pub sample_rate: f64,
 
-    pub buffer: Vec<f32>,
+    pub buffer: Vec<f32>, // stereo output at exactly sample_rate, resampled for the audio device by Resampler
+
+    pub record_stems: bool,
+    pub stems: [Vec<f32>; 4], // unmixed mono channel output, ignores the dbgch mutes
 
     pub dbgch1: bool,
     pub dbgch2: bool,
     pub dbgch3: bool,
     pub dbgch4: bool, 
+    pub muted: bool,
+
+    pub cart_tone: bool, // HuC3 cartridge speaker
+    pub cart_tone_phase: f64, // position in the square wave period, 0.0-1.0
+
+    pub regs: [u8; 0x30], // last value written to 0xFF10-0xFF3F
+    pub vgm: Option<VgmLogger>,
 }
 
 impl Apu {
@@ -50,12 +72,37 @@ impl Apu {
             ch4: Noise::default(),
             sequencer_step: 0,
             div_bit: false,
+            cycles: 0,
             sample_counter: 0.0,
+            sample_rate: SAMPLE_RATE,
             buffer: Vec::new(),
+            record_stems: false,
+            stems: [Vec::new(), Vec::new(), Vec::new(), Vec::new()],
             dbgch1: true,
             dbgch2: true,
             dbgch3: true,
             dbgch4: true,
+            muted: false,
+            cart_tone: false,
+            cart_tone_phase: 0.0,
+            regs: [0; 0x30],
+            vgm: None,
+        }
+    }
+
+    // Power on state, the output settings, mutes and a running vgm log are kept
+    pub fn reset(self) -> Self {
+        Apu{
+            cycles: self.cycles,
+            sample_rate: self.sample_rate,
+            record_stems: self.record_stems,
+            dbgch1: self.dbgch1,
+            dbgch2: self.dbgch2,
+            dbgch3: self.dbgch3,
+            dbgch4: self.dbgch4,
+            muted: self.muted,
+            vgm: self.vgm,
+            ..Apu::new()
         }
     }
 
@@ -80,13 +127,13 @@ impl Apu {
     }
     
     pub fn tick(&mut self, divider: u8) {
-        const SAMPLE_RATE: f64 = 22_050_f64;
-        const SAMPLE_EVERY_N_TICKS: f64 = 4_194_304_f64 / SAMPLE_RATE;
+        self.cycles += 1;
+        let sample_every_n_ticks = CPU_FREQ / self.sample_rate;
 
         self.sample_counter += 1.0;
-        if self.sample_counter >= SAMPLE_EVERY_N_TICKS {
+        if self.sample_counter >= sample_every_n_ticks {
             self.push_output();
-            self.sample_counter -= SAMPLE_EVERY_N_TICKS;
+            self.sample_counter -= sample_every_n_ticks;
         }
 
         if !self.enable {
@@ -140,10 +187,22 @@ impl Apu {
         let rvol = self.rvol as f32 + 1.0;
         let lvol = self.lvol as f32 + 1.0;
 
-        let ch1 = if self.dbgch1 {self.ch1.dac_output() / 8.0}else {0.0};
-        let ch2 = if self.dbgch2 {self.ch2.dac_output() / 8.0}else {0.0};
-        let ch3 = if self.dbgch3 {self.ch3.dac_output() / 8.0}else {0.0};
-        let ch4 = if self.dbgch4 {self.ch4.dac_output() / 8.0}else {0.0};
+        let dac = [
+            self.ch1.dac_output(),
+            self.ch2.dac_output(),
+            self.ch3.dac_output(),
+            self.ch4.dac_output(),
+        ];
+        if self.record_stems {
+            for (stem, sample) in self.stems.iter_mut().zip(dac) {
+                stem.push(sample);
+            }
+        }
+
+        let ch1 = if self.dbgch1 {dac[0] / 8.0}else {0.0};
+        let ch2 = if self.dbgch2 {dac[1] / 8.0}else {0.0};
+        let ch3 = if self.dbgch3 {dac[2] / 8.0}else {0.0};
+        let ch4 = if self.dbgch4 {dac[3] / 8.0}else {0.0};
 
         let ch1_right = if self.ch1.right_enable {ch1 * rvol}else {0.0};
         let ch2_right = if self.ch2.right_enable {ch2 * rvol}else {0.0};
@@ -155,8 +214,17 @@ impl Apu {
         let ch3_left = if self.ch3.left_enable {ch3 * lvol}else {0.0};
         let ch4_left = if self.ch4.left_enable {ch4 * lvol}else {0.0};
 
-        let right_sample = ch1_right + ch2_right + ch3_right + ch4_right;
-        let left_sample = ch1_left + ch2_left + ch3_left + ch4_left;
+        // About 2.2kHz square wave, the cartridge speaker isn't affected by the master volume.
+        // Stepped by the output rate so the pitch follows the emulated time like the channels.
+        let tone = if self.cart_tone {
+            self.cart_tone_phase = (self.cart_tone_phase + CART_TONE_FREQ / self.sample_rate).fract();
+            if self.cart_tone_phase < 0.5 {0.25} else {-0.25}
+        }else {
+            0.0
+        };
+
+        let right_sample = ch1_right + ch2_right + ch3_right + ch4_right + tone;
+        let left_sample = ch1_left + ch2_left + ch3_left + ch4_left + tone;
         
         self.buffer.push(left_sample);
         self.buffer.push(right_sample);
@@ -200,6 +268,10 @@ impl Apu {
     }
 
     pub fn writeu8(&mut self, addr: u16, val: u8) {
+        self.regs[(addr - 0xFF10) as usize] = val;
+        if let Some(vgm) = &mut self.vgm {
+            vgm.log_write(self.cycles, addr, val);
+        }
         if !self.enable && addr <= 0xFF25 && (addr % 5 != 2 || addr == 0xFF25) {
             return;
         }
@@ -290,7 +362,64 @@ impl Apu {
         self.enable = new_enable;
     }
 
+    pub fn clear_stems(&mut self) {
+        for stem in self.stems.iter_mut() {
+            stem.clear();
+        }
+    }
+
+    pub fn start_vgm(&mut self, path: PathBuf) {
+        self.vgm = Some(VgmLogger::new(path, self.cycles, &self.regs));
+    }
+
+    pub fn stop_vgm(&mut self) -> Option<io::Result<PathBuf>> {
+        self.vgm.take().map(|vgm| vgm.finish(self.cycles))
+    }
+
+    pub fn mark_vgm_loop(&mut self) {
+        if let Some(vgm) = &mut self.vgm {
+            vgm.mark_loop(self.cycles);
+        }
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted || !(self.dbgch1 || self.dbgch2 || self.dbgch3 || self.dbgch4)
+    }
+
     pub fn is_length_clock_next(&self) -> bool {
         (self.sequencer_step % 2) == 0
     }
+}
+
+// Output buffers, debugging mutes and the tstate timestamps of the vgm log are not machine state
+savestate_fields!(Apu {
+    lvol, rvol, lvin, rvin, enable, ch1, ch2, ch3, ch4,
+    sequencer_step, div_bit, sample_counter, cart_tone, cart_tone_phase, regs,
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pacer::Resampler;
+    use crate::TSTATES_PER_FRAME;
+
+    // What the wav recorder gets for some emulated time doesn't depend on how the queue output is resampled
+    #[test]
+    fn output_rate_is_fixed() {
+        let frames = 10;
+        let expected = (frames * TSTATES_PER_FRAME) as f64 * SAMPLE_RATE / CPU_FREQ;
+        for ratio in [0.25, 0.995, 1.0, 1.005] {
+            let mut apu = Apu::new();
+            let mut resampler = Resampler::new();
+            let (mut recorded, mut queued) = (0, 0);
+            for _ in 0..frames {
+                (0..TSTATES_PER_FRAME).for_each(|_| apu.tick(0));
+                let buffer = std::mem::take(&mut apu.buffer);
+                recorded += buffer.len() / 2;
+                queued += resampler.resample(&buffer, ratio).len() / 2;
+            }
+            assert!((recorded as f64 - expected).abs() <= 1.0, "ratio {}: {} samples", ratio, recorded);
+            assert!((queued as f64 - recorded as f64 * ratio).abs() <= 1.0, "ratio {}: {} queued", ratio, queued);
+        }
+    }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 use super::{envelope::Envelope, lengthcounter::LengthCounter};
+use crate::savestate::savestate_fields;
 
 #[derive(Debug)]
 pub struct Noise{
@@ -153,4 +154,9 @@ divisor:
 110
 
 
-*/
\ No newline at end of file
+*/
+
+savestate_fields!(Noise {
+    left_enable, right_enable, enabled, envelope, length_counter,
+    shift_clock_freq, width_mode, divisor_code, freq_timer, lsfr, dac_enable, dac_capacitor,
+});
\ No newline at end of file
@@ -1,5 +1,6 @@
 use super::envelope::Envelope;
 use super::lengthcounter::LengthCounter;
+use crate::savestate::savestate_fields;
 
 #[derive(Debug)]
 pub struct Square{
@@ -194,4 +195,10 @@ impl Square {
         }
     }
     
-}
\ No newline at end of file
+}
+
+savestate_fields!(Square {
+    left_enable, right_enable, enabled, envelope, length_counter,
+    sweep_period, sweep_negate, sweep_shift, freq_shadow, sweep_enable, sweep_timer,
+    freq, freq_timer, duty, phase, dac_enable, dac_capacitor,
+});
\ No newline at end of file
@@ -0,0 +1,100 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+const VGM_VERSION: u32 = 0x0000_0161;
+const VGM_SAMPLE_RATE: u64 = 44_100;
+const DMG_CLOCK: u64 = 4_194_304;
+const HEADER_SIZE: usize = 0x100;
+
+// Logs APU register writes as VGM commands, timestamps are in emulated tstates
+#[derive(Debug)]
+pub struct VgmLogger {
+    pub path: PathBuf,
+    data: Vec<u8>,
+    start_cycle: u64,
+    samples: u64,
+    loop_point: Option<(usize, u64)>, // (data offset, samples at the loop)
+}
+
+impl VgmLogger {
+    // regs holds the last value written to every register from 0xFF10 to 0xFF3F,
+    // they are replayed first so the log starts from the current sound state
+    pub fn new(path: PathBuf, cycle: u64, regs: &[u8; 0x30]) -> Self {
+        let mut logger = VgmLogger{
+            path,
+            data: Vec::new(),
+            start_cycle: cycle,
+            samples: 0,
+            loop_point: None,
+        };
+        logger.write_reg(0x16, regs[0x16]);
+        logger.write_reg(0x0A, 0x00);
+        for offset in 0x20..0x30 {
+            logger.write_reg(offset, regs[offset as usize]);
+        }
+        for offset in 0x00..0x16 {
+            let val = match offset {
+                0x04 | 0x09 | 0x0E | 0x13 => regs[offset as usize] & 0x7F, // no retrigger
+                _ => regs[offset as usize],
+            };
+            logger.write_reg(offset, val);
+        }
+        logger
+    }
+
+    fn write_reg(&mut self, offset: u8, val: u8) {
+        self.data.extend_from_slice(&[0xB3, offset, val]);
+    }
+
+    fn wait_until(&mut self, cycle: u64) {
+        let target = (cycle - self.start_cycle) * VGM_SAMPLE_RATE / DMG_CLOCK;
+        let mut wait = target.saturating_sub(self.samples);
+        self.samples += wait;
+        while wait > 0 {
+            let n = wait.min(0xFFFF);
+            match n {
+                735 => self.data.push(0x62),
+                882 => self.data.push(0x63),
+                1..=16 => self.data.push(0x70 + (n - 1) as u8),
+                _ => {
+                    self.data.push(0x61);
+                    self.data.extend_from_slice(&(n as u16).to_le_bytes());
+                }
+            }
+            wait -= n;
+        }
+    }
+
+    pub fn log_write(&mut self, cycle: u64, addr: u16, val: u8) {
+        self.wait_until(cycle);
+        self.write_reg((addr - 0xFF10) as u8, val);
+    }
+
+    pub fn mark_loop(&mut self, cycle: u64) {
+        self.wait_until(cycle);
+        self.loop_point = Some((self.data.len(), self.samples));
+    }
+
+    pub fn finish(mut self, cycle: u64) -> io::Result<PathBuf> {
+        self.wait_until(cycle);
+        self.data.push(0x66);
+
+        let mut header = vec![0u8; HEADER_SIZE];
+        let mut put = |offset: usize, val: u32| header[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+        put(0x04, (HEADER_SIZE + self.data.len() - 0x04) as u32);
+        put(0x08, VGM_VERSION);
+        put(0x18, self.samples as u32);
+        if let Some((offset, samples)) = self.loop_point {
+            put(0x1C, (HEADER_SIZE + offset - 0x1C) as u32);
+            put(0x20, (self.samples - samples) as u32);
+        }
+        put(0x34, (HEADER_SIZE - 0x34) as u32);
+        put(0x80, DMG_CLOCK as u32);
+        header[0x00..0x04].copy_from_slice(b"Vgm ");
+
+        header.extend_from_slice(&self.data);
+        fs::write(&self.path, header)?;
+        Ok(self.path)
+    }
+}
@@ -1,4 +1,5 @@
 use super::lengthcounter::LengthCounter;
+use crate::savestate::savestate_fields;
 
 #[derive(Debug)]
 pub struct Wave{
@@ -15,6 +16,7 @@ pub struct Wave{
 
     pub wave_table: [u8; 16],
     pub table_index: u8,
+    pub ticks_since_read: u16, // tstates since the channel last fetched a wave ram byte
 
     pub dac_enable: bool,
 
@@ -23,6 +25,13 @@ pub struct Wave{
 
 const VOL_SHIFT: [u8; 4] = [4, 0, 1, 2];
 
+// On DMG the cpu can only reach wave ram while the channel plays during the
+// 2 tstate window in which the channel itself is reading it.
+// The bus ticks the apu up to the access, counting the instruction's internal machine cycles too,
+// and takes it as happening at the start of its machine cycle. blargg's dmg_sound 09, 10 and 12
+// cover this, see the ignored tests in main.rs.
+const DMG_WAVE_ACCESS_WINDOW: u16 = 2;
+
 impl Default for Wave{
     fn default() -> Self {
         Self{
@@ -35,6 +44,7 @@ impl Default for Wave{
             vol: 0,
             wave_table: [0; 16],
             table_index: 0,
+            ticks_since_read: u16::MAX,
             dac_enable: false,
             dac_capacitor: 0.0,
         }
@@ -46,13 +56,18 @@ impl Wave {
     }
 
     pub fn tick(&mut self) {
+        self.ticks_since_read = self.ticks_since_read.saturating_add(1);
         self.freq_timer = self.freq_timer.saturating_sub(1);
         if self.freq_timer == 0 {
             self.freq_timer = 2*(2048 - self.freq);
             self.table_index = (self.table_index + 1) & 0x1F;
-
+            self.ticks_since_read = 0;
         }
     }
+
+    pub fn is_wave_ram_accessible(&self) -> bool {
+        self.ticks_since_read < DMG_WAVE_ACCESS_WINDOW
+    }
     
     pub fn output(&self) -> u8 {
         if !self.enabled{
@@ -124,30 +139,150 @@ impl Wave {
         }
     }
 
+    // While playing, accesses go to the byte the channel is reading instead of the given offset,
+    // and only succeed on the cycle it reads it. Otherwise reads return 0xFF and writes are dropped.
     pub fn read_wave_ram(&self, offset: usize) -> u8 {
-        let index = if self.dac_enable && self.enabled {
-            (self.table_index >> 1) as usize
+        if !self.enabled {
+            self.wave_table[offset & 0x0F]
+        }else if self.is_wave_ram_accessible() {
+            self.wave_table[(self.table_index >> 1) as usize]
         }else {
-            offset
-        } & 0x0F;
-        self.wave_table[index]
+            0xFF
+        }
     }
 
     pub fn write_wave_ram(&mut self, offset: usize, val: u8) {
-        let index = if self.dac_enable && self.enabled {
-            (self.table_index >> 1) as usize
+        if !self.enabled {
+            self.wave_table[offset & 0x0F] = val;
+        }else if self.is_wave_ram_accessible() {
+            self.wave_table[(self.table_index >> 1) as usize] = val;
+        }
+    }
+
+    // DMG only: retriggering while the channel is about to read wave ram corrupts the first bytes.
+    // The byte being read is copied to byte 0 if it's in the first 4 bytes,
+    // otherwise its aligned 4 byte block is copied over bytes 0-3.
+    pub fn corrupt_wave_ram(&mut self) {
+        let position = (((self.table_index + 1) & 0x1F) >> 1) as usize;
+        if position < 4 {
+            self.wave_table[0] = self.wave_table[position];
         }else {
-            offset
-        } & 0x0F;
-        self.wave_table[index] = val;
+            let block = position & !0x03;
+            self.wave_table.copy_within(block..block + 4, 0);
+        }
     }
 
+    // freq_timer <= 2 means the next fetch falls in the apu cycle the write happens in
     pub fn trigger(&mut self, length_next: bool) {
+        if self.enabled && self.freq_timer <= DMG_WAVE_ACCESS_WINDOW {
+            self.corrupt_wave_ram();
+        }
         if self.dac_enable {
             self.enabled = true;
         }
         self.length_counter.trigger(length_next);
         self.table_index = 0;
+        self.ticks_since_read = u16::MAX;
         self.freq_timer = 2*((2048 - self.freq) + 2);
     }
+}
+
+savestate_fields!(Wave {
+    left_enable, right_enable, enabled, length_counter, freq, freq_timer, vol,
+    wave_table, table_index, ticks_since_read, dac_enable, dac_capacitor,
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Playing channel with a 16 tstate sample period, the first fetch is 20 tstates after the trigger
+    fn playing() -> Wave {
+        let mut wave = Wave{wave_table: std::array::from_fn(|i| 0x10 + i as u8), ..Wave::default()};
+        wave.write_nr30(0x80);
+        wave.write_nr33(0xF8);
+        wave.write_nr34(false, 0x87);
+        wave
+    }
+
+    fn tick(wave: &mut Wave, tstates: usize) {
+        (0..tstates).for_each(|_| wave.tick());
+    }
+
+    #[test]
+    fn stopped_channel_accesses_the_offset() {
+        let mut wave = Wave{wave_table: std::array::from_fn(|i| 0x10 + i as u8), ..Wave::default()};
+        assert_eq!(wave.read_wave_ram(5), 0x15);
+        wave.write_wave_ram(5, 0xAB);
+        assert_eq!(wave.wave_table[5], 0xAB);
+
+        let mut wave = playing();
+        wave.write_nr30(0x00);
+        assert_eq!(wave.read_wave_ram(9), 0x19);
+    }
+
+    #[test]
+    fn playing_channel_readable_window() {
+        let mut wave = playing();
+        assert_eq!(wave.read_wave_ram(9), 0xFF);
+        tick(&mut wave, 19);
+        assert_eq!(wave.read_wave_ram(9), 0xFF);
+
+        // Fetches byte 0 for sample 1, readable on that tstate and the next
+        tick(&mut wave, 1);
+        assert_eq!(wave.read_wave_ram(9), 0x10);
+        tick(&mut wave, 1);
+        wave.write_wave_ram(9, 0xAB);
+        assert_eq!(wave.wave_table[0], 0xAB);
+        assert_eq!(wave.wave_table[9], 0x19);
+
+        tick(&mut wave, 1);
+        assert_eq!(wave.read_wave_ram(9), 0xFF);
+        wave.write_wave_ram(9, 0xCD);
+        assert_eq!(wave.wave_table[0], 0xAB);
+
+        // Next fetch 16 tstates after the first is byte 1 for sample 2
+        tick(&mut wave, 13);
+        assert_eq!(wave.read_wave_ram(0), 0xFF);
+        tick(&mut wave, 1);
+        assert_eq!(wave.read_wave_ram(0), 0x11);
+    }
+
+    #[test]
+    fn retrigger_corrupts_first_bytes() {
+        // About to fetch byte 0 or 1 -> that byte is copied over byte 0
+        let mut wave = playing();
+        tick(&mut wave, 18);
+        wave.write_nr34(false, 0x87);
+        assert_eq!(wave.wave_table[0..4], [0x10, 0x11, 0x12, 0x13]);
+        tick(&mut wave, 20 + 16);
+        assert_eq!(wave.table_index, 2);
+        tick(&mut wave, 14);
+        wave.write_nr34(false, 0x87);
+        assert_eq!(wave.wave_table[0..4], [0x11, 0x11, 0x12, 0x13]);
+
+        // About to fetch byte 5 -> bytes 4-7 are copied over 0-3
+        let mut wave = playing();
+        wave.table_index = 9;
+        wave.freq_timer = 1;
+        wave.write_nr34(false, 0x87);
+        assert_eq!(wave.wave_table[0..8], [0x14, 0x15, 0x16, 0x17, 0x14, 0x15, 0x16, 0x17]);
+    }
+
+    #[test]
+    fn retrigger_off_the_fetch_cycle_keeps_wave_ram() {
+        let mut wave = playing();
+        wave.table_index = 9;
+        wave.freq_timer = 3;
+        wave.write_nr34(false, 0x87);
+        assert_eq!(wave.wave_table[0], 0x10);
+
+        let mut wave = playing();
+        wave.table_index = 9;
+        wave.freq_timer = 1;
+        wave.write_nr30(0x00);
+        wave.write_nr30(0x80);
+        wave.write_nr34(false, 0x87);
+        assert_eq!(wave.wave_table[0], 0x10);
+    }
 }
\ No newline at end of file
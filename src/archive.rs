@@ -0,0 +1,73 @@
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use zip::ZipArchive;
+
+// Entries picked from a zip when no name is given, compared case insensitively
+const ROM_EXTENSIONS: [&str; 4] = ["gb", "gbc", "sgb", "gbs"];
+
+// The largest cartridges are 8MiB, anything much bigger is not a rom
+const MAX_ROM_SIZE: u64 = 16 << 20;
+
+// Reads a rom, unpacking it first when the file is a zip or gzip archive.
+// entry selects the zip member by path or file name, otherwise the first rom-like one is used.
+// Gzip and plain files hold a single rom so entry is an error there.
+pub fn read_rom(path: &str, entry: Option<&str>) -> Result<Vec<u8>, String> {
+    let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    let rom = match data.get(0..4) {
+        Some(b"PK\x03\x04") | Some(b"PK\x05\x06") => read_zip(data, entry),
+        Some(b"7z\xBC\xAF") => Err("7z archives are not supported, use zip or gzip".to_string()),
+        _ if entry.is_some() => Err("--entry only applies to zip archives".to_string()),
+        Some([0x1F, 0x8B, _, _]) => read_all(GzDecoder::new(&data[..])),
+        _ => return Ok(data),
+    };
+    rom.map_err(|err| format!("{}: {}", path, err))
+}
+
+fn read_zip(data: Vec<u8>, entry: Option<&str>) -> Result<Vec<u8>, String> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(|err| err.to_string())?;
+    let mut names = Vec::new();
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i).map_err(|err| err.to_string())?;
+        if file.is_file() {
+            names.push(file.name().to_string());
+        }
+    }
+    let name = match entry {
+        Some(entry) => names.into_iter()
+            .find(|name| name.as_str() == entry || file_name(name) == entry)
+            .ok_or(format!("no entry named {} in the archive", entry))?,
+        None => names.into_iter()
+            .find(|name| is_rom_name(name))
+            .ok_or("no Game Boy rom found in the archive, pick one with --entry <name>")?,
+    };
+    let file = archive.by_name(&name).map_err(|err| err.to_string())?;
+    read_all(file)
+}
+
+fn read_all(reader: impl Read) -> Result<Vec<u8>, String> {
+    let mut rom = Vec::new();
+    reader.take(MAX_ROM_SIZE + 1).read_to_end(&mut rom).map_err(|err| err.to_string())?;
+    if rom.len() as u64 > MAX_ROM_SIZE {
+        return Err("unpacked rom is too large".to_string());
+    }
+    Ok(rom)
+}
+
+fn file_name(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    let ext = Path::new(name).extension().unwrap_or_default().to_string_lossy().to_lowercase();
+    ROM_EXTENSIONS.contains(&ext.as_str())
+}
+
+// Path that save and log files are named after, "game.zip" and "game.gb.gz" both give "game.*"
+pub fn base_path(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    let is_gz = path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("gz"));
+    if is_gz {path.with_extension("")} else {path.to_path_buf()}
+}
@@ -1,6 +1,7 @@
 use crate::cartridge::Cartridge;
 use super::timer::Timer;
 use super::apu::Apu;
+use crate::savestate::savestate_fields;
 
 #[derive(Debug)]
 pub struct Bus{
@@ -52,12 +53,18 @@ pub struct Bus{
     pub ime: bool,
     pub imebuf: bool,
     pub is_cpu_halt : bool,
+    pub is_cpu_stop: bool, // STOP low power mode, left when a selected joypad line goes low
     pub is_boot_rom: bool,
     pub is_oam_dma: bool,
     pub is_ppu_mode23: bool,
     pub is_ppu_mode3: bool,
     pub is_vram_block: bool,
 
+    // The cpu runs a whole instruction before the other components are ticked for it. These track
+    // the tstate within the instruction of each cpu access so the apu can be brought up to it first.
+    pub cpu_access_tstates: Option<usize>, // None outside of an instruction
+    pub apu_ticked_ahead: usize, // tstates of the current instruction the apu was already ticked for
+
 
     pub jpad_down: bool,
     pub jpad_up: bool,
@@ -106,12 +113,16 @@ impl Bus {
             ime: false,
             imebuf: false,
             is_cpu_halt: false,
+            is_cpu_stop: false,
             is_boot_rom: true,
             is_oam_dma: false,
             is_ppu_mode23: true,
             is_ppu_mode3: false,
             is_vram_block: false,
 
+            cpu_access_tstates: None,
+            apu_ticked_ahead: 0,
+
 
             jpad_down: false,
             jpad_up: false,
@@ -125,7 +136,29 @@ impl Bus {
             debug_inst: false,
         }
     }
+    // Soft reset reruns the boot rom on the memory contents left from before,
+    // hard reset clears them like a power cycle. The cartridge keeps its ram either way.
+    pub fn reset(self, is_hard: bool) -> Bus {
+        let joypad = self.joypad();
+        let debug_inst = self.debug_inst;
+        let Bus{mut cart, apu, vram, wram0, wramn, oam, hram, ..} = self;
+        cart.reset();
+        let mut bus = Bus::new(cart);
+        bus.apu = apu.reset();
+        if !is_hard {
+            bus.vram = vram;
+            bus.wram0 = wram0;
+            bus.wramn = wramn;
+            bus.oam = oam;
+            bus.hram = hram;
+        }
+        bus.set_joypad(joypad);
+        bus.debug_inst = debug_inst;
+        bus
+    }
+
     pub fn after_bootup(&mut self) {
+        self.is_boot_rom = false;
         self.p1 = 0xCF;
         self.sb = 0x00;
         self.sc = 0x7E;
@@ -148,6 +181,7 @@ impl Bus {
         self.ie = 0x00;
     }
     pub fn readu8(&mut self, addr: u16) -> u8 {
+        let access = self.cpu_access();
         if !self.is_oam_dma {
             match addr {
                 0x0000..=0x00FF if self.is_boot_rom => self.cart.read_bootrom(addr),
@@ -160,9 +194,7 @@ impl Bus {
                 0xFE00..=0xFE9F if !self.is_ppu_mode23 => self.oam[(addr & 0x00FF) as usize],
                 0xFE00..=0xFE9F => 0xFF,
                 0xFEA0..=0xFEFF => 0xFF,
-                0xFF00 if (self.p1 & 0x20) == 0 => self.read_act(),
-                0xFF00 if (self.p1 & 0x10) == 0 => self.read_dir(),
-                0xFF00 => 0xCF,
+                0xFF00 => 0xC0|(self.p1 & 0x30)|self.joypad_lines(),
                 0xFF01 => self.sb,
                 0xFF02 => self.sc,
                 0xFF03 => 0xFF,
@@ -172,6 +204,10 @@ impl Bus {
                 0xFF07 => self.timer.read_tac(),
                 0xFF08..=0xFF0F => 0xFF,
                 
+                0xFF1A..=0xFF1E | 0xFF30..=0xFF3F => {
+                    self.sync_apu(access);
+                    self.apu.readu8(addr)
+                }
                 0xFF10..=0xFF3F => self.apu.readu8(addr),
 
                 0xFF40 => self.lcdc,
@@ -203,18 +239,26 @@ impl Bus {
     }
 
     pub fn writeu8(&mut self, addr: u16, val: u8) {
+        let access = self.cpu_access();
         if !self.is_oam_dma {
             match addr{
                 0x0000..=0x7FFF => self.cart.writeu8(addr, val),
                 0x8000..=0x9FFF if !self.is_ppu_mode3 => self.vram[(addr & 0x1FFF) as usize] = val,
                 0x8000..=0x9FFF => (),
-                0xA000..=0xBFFF => self.cart.writeu8(addr, val),
+                0xA000..=0xBFFF => {
+                    self.cart.writeu8(addr, val);
+                    self.apu.cart_tone = self.cart.is_tone_active();
+                }
                 0xC000..=0xCFFF | 0xE000..=0xEFFF => self.wram0[(addr & 0x0FFF) as usize] = val,
                 0xD000..=0xDFFF | 0xF000..=0xFDFF => self.wramn[(addr & 0x0FFF) as usize] = val,
                 0xFE00..=0xFE9F if !self.is_ppu_mode23 => self.oam[(addr & 0x00FF) as usize] = val,
                 0xFE00..=0xFE9F => (),
                 0xFEA0..=0xFEFF => (),
-                0xFF00 => self.p1 = (val & 0x30)|(self.p1 & 0xCF),
+                0xFF00 => {
+                    let lines = self.joypad_lines();
+                    self.p1 = (val & 0x30)|(self.p1 & 0xCF);
+                    self.joypad_irq(lines);
+                }
                 0xFF01 => self.sb = val,
                 0xFF02 => self.sc = val,
                 0xFF03 => (),
@@ -224,6 +268,10 @@ impl Bus {
                 0xFF07 => self.timer.write_tac(val),
                 0xFF08..=0xFF0F => (),
 
+                0xFF1A..=0xFF1E | 0xFF30..=0xFF3F => {
+                    self.sync_apu(access);
+                    self.apu.writeu8(addr, val);
+                }
                 0xFF10..=0xFF3F => self.apu.writeu8(addr, val),
 
                 0xFF40 => self.lcdc = val,
@@ -251,6 +299,33 @@ impl Bus {
             }
         }
     }
+    // Each cpu memory access takes one 4 tstate machine cycle, returns the tstate it starts at
+    fn cpu_access(&mut self) -> Option<usize> {
+        let tstates = self.cpu_access_tstates?;
+        self.cpu_access_tstates = Some(tstates + 4);
+        Some(tstates)
+    }
+
+    // Machine cycle the cpu spends without touching memory, called by instructions that have one before an access
+    pub fn cpu_idle(&mut self) {
+        if let Some(tstates) = &mut self.cpu_access_tstates {
+            *tstates += 4;
+        }
+    }
+
+    // Channel 3 wave ram accesses and retriggers depend on the exact cycle the channel fetches a sample,
+    // so the apu is ticked up to the access instead of waiting for the end of the instruction.
+    // The divider it sees is the one the timer will have reached by then.
+    fn sync_apu(&mut self, access: Option<usize>) {
+        let Some(tstates) = access else {
+            return;
+        };
+        while self.apu_ticked_ahead < tstates {
+            self.apu_ticked_ahead += 1;
+            self.apu.tick((self.timer.div.wrapping_add(self.apu_ticked_ahead as u16) >> 8) as u8);
+        }
+    }
+
     pub fn ppuread(&mut self, addr: u16) -> u8 {
         match addr {
             0x8000..=0x9FFF if self.is_vram_block => 0xFF,
@@ -286,5 +361,100 @@ impl Bus {
         let act = (((!self.jpad_start) as u8) << 3)|(((!self.jpad_select) as u8) << 2)|(((!self.jpad_b) as u8) << 1)|((!self.jpad_a) as u8);
         pressed|act
     }
+
+    // Low nibble of P1, the selected groups are ANDed together and all lines read high when neither is selected
+    pub fn joypad_lines(&self) -> u8 {
+        let mut lines = 0x0F;
+        if (self.p1 & 0x10) == 0 {
+            lines &= self.read_dir();
+        }
+        if (self.p1 & 0x20) == 0 {
+            lines &= self.read_act();
+        }
+        lines & 0x0F
+    }
+
+    // The joypad interrupt is requested when any of the lines goes from high to low,
+    // either from a button press or from selecting a group with a button already held
+    fn joypad_irq(&mut self, lines: u8) {
+        if (lines & !self.joypad_lines()) != 0 {
+            self.iff |= 0x10;
+        }
+    }
+
+    // All eight buttons as one byte, 1 = pressed, directions in the low nibble like P1
+    pub fn joypad(&self) -> u8 {
+        [self.jpad_right, self.jpad_left, self.jpad_up, self.jpad_down, self.jpad_a, self.jpad_b, self.jpad_select, self.jpad_start]
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (i, &pressed)| acc | ((pressed as u8) << i))
+    }
+
+    pub fn set_joypad(&mut self, state: u8) {
+        let lines = self.joypad_lines();
+        let pressed = |i: u8| (state & (1 << i)) != 0;
+        self.jpad_right = pressed(0);
+        self.jpad_left = pressed(1);
+        self.jpad_up = pressed(2);
+        self.jpad_down = pressed(3);
+        self.jpad_a = pressed(4);
+        self.jpad_b = pressed(5);
+        self.jpad_select = pressed(6);
+        self.jpad_start = pressed(7);
+        self.joypad_irq(lines);
+    }
     
 } 
+
+// The joypad is the player's input rather than machine state
+savestate_fields!(Bus {
+    cart, timer, apu, vram, wram0, wramn, oam, p1, sb, sc, iff,
+    lcdc, stat, scy, scx, ly, lyc, dma, wy, wx, bgp, obp0, obp1, hram, ie,
+    ime, imebuf, is_cpu_halt, is_cpu_stop, is_boot_rom, is_oam_dma, is_ppu_mode23, is_ppu_mode3, is_vram_block,
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    #[test]
+    fn wave_ram_access_sees_the_apu_at_its_machine_cycle() {
+        let mut bus = Bus::new(Cartridge::new(vec![0u8; 0x8000], Vec::new()));
+        // Channel 3 with a 16 tstate sample period, first fetch of byte 0 20 tstates after the trigger
+        bus.writeu8(0xFF26, 0x80);
+        bus.writeu8(0xFF30, 0x5A);
+        bus.writeu8(0xFF1A, 0x80);
+        bus.writeu8(0xFF1D, 0xF8);
+        bus.writeu8(0xFF1E, 0x87);
+
+        // Like step() running an instruction, four machine cycles elsewhere then wave ram at tstate 16 and 20
+        bus.cpu_access_tstates = Some(0);
+        bus.apu_ticked_ahead = 0;
+        (0..4).for_each(|_| {bus.readu8(0xC000);});
+        assert_eq!(bus.apu_ticked_ahead, 0);
+        assert_eq!(bus.readu8(0xFF30), 0xFF);
+        assert_eq!(bus.apu_ticked_ahead, 16);
+        assert_eq!(bus.readu8(0xFF30), 0x5A);
+        assert_eq!(bus.apu_ticked_ahead, 20);
+    }
+
+    #[test]
+    fn push_writes_after_its_internal_cycle() {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0100] = 0xC5; // PUSH BC
+        let mut bus = Bus::new(Cartridge::new(rom, Vec::new()));
+        bus.after_bootup();
+        let mut cpu = Cpu::new();
+        cpu.after_bootup();
+        bus.writeu8(0xFF26, 0x80);
+        cpu.sp = 0xFF40;
+
+        // Fetch, internal cycle, then wave ram at tstate 8 and 12
+        bus.cpu_access_tstates = Some(0);
+        bus.apu_ticked_ahead = 0;
+        assert_eq!(cpu.clock(&mut bus), 4);
+        assert_eq!(bus.apu_ticked_ahead, 12);
+        assert_eq!(bus.cpu_access_tstates, Some(16));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,206 @@
+use std::fs;
+use crate::savestate::savestate_fields;
+
+// Game Boy Camera M64282FP sensor, the picture comes from a still image or a generated test pattern
+pub const WIDTH: usize = 128;
+pub const HEIGHT: usize = 112;
+
+// Captured image is written as 2bpp tiles at the start of sram bank 0
+const IMAGE_OFFSET: usize = 0x0100;
+
+const EDGE_RATIO: [f32; 8] = [0.5, 0.75, 1.0, 1.25, 2.0, 3.0, 4.0, 5.0];
+
+#[derive(Debug, Clone)]
+pub struct Camera {
+    pub regs: [u8; 0x36], // A000-A035, A006-A035 hold the 4x4 matrix of 3 dithering thresholds
+    pub capture_timer: usize, // tstates until the running capture completes
+    pub image: Option<Vec<u8>>, // WIDTH x HEIGHT grayscale, test pattern if none
+    pub frame: usize,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Camera {
+    pub fn new() -> Self {
+        Camera{
+            regs: [0; 0x36],
+            capture_timer: 0,
+            image: None,
+            frame: 0,
+        }
+    }
+
+    pub fn read_reg(&self, addr: u16) -> u8 {
+        // Only the control register can be read, bit 0 stays set while capturing
+        match addr & 0x7F {
+            0x00 => (self.regs[0] & 0x06) | (self.capture_timer > 0) as u8,
+            _ => 0x00,
+        }
+    }
+
+    pub fn write_reg(&mut self, addr: u16, val: u8) {
+        let reg = (addr & 0x7F) as usize;
+        if reg >= self.regs.len() {
+            return;
+        }
+        if reg == 0x00 {
+            self.regs[0] = val & 0x07;
+            if (val & 0x01) != 0 && self.capture_timer == 0 {
+                let n = (self.regs[1] & 0x80) != 0;
+                let exposure = self.exposure() as usize;
+                self.capture_timer = 4*(32446 + if n {0} else {512} + 16*exposure);
+            }else if (val & 0x01) == 0 {
+                self.capture_timer = 0;
+            }
+        }else {
+            self.regs[reg] = val;
+        }
+    }
+
+    pub fn exposure(&self) -> u16 {
+        u16::from_be_bytes([self.regs[2], self.regs[3]])
+    }
+
+    // Returns true when a capture finished during these tstates
+    pub fn tick(&mut self, tstates: usize) -> bool {
+        if self.capture_timer == 0 {
+            return false;
+        }
+        self.capture_timer = self.capture_timer.saturating_sub(tstates);
+        if self.capture_timer == 0 {
+            self.regs[0] &= !0x01;
+            return true;
+        }
+        false
+    }
+
+    // Loads a binary or ascii PGM / PPM picture scaled to the sensor size
+    pub fn load_image(&mut self, path: &str) -> Result<(), String> {
+        let data = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        let (width, height, pixels) = parse_pnm(&data).ok_or(format!("{}: not a PGM / PPM image", path))?;
+        let mut image = vec![0u8; WIDTH*HEIGHT];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                image[y*WIDTH + x] = pixels[(y*height / HEIGHT)*width + x*width / WIDTH];
+            }
+        }
+        self.image = Some(image);
+        Ok(())
+    }
+
+    fn sensor(&self, x: usize, y: usize) -> f32 {
+        let x = x.min(WIDTH - 1);
+        let y = y.min(HEIGHT - 1);
+        match &self.image {
+            Some(image) => image[y*WIDTH + x] as f32,
+            None => test_pattern(x, y, self.frame) as f32,
+        }
+    }
+
+    // Exposure, gain and edge enhancement, then the dithering matrix turns the result into 2bpp tiles
+    pub fn capture(&mut self, sram: &mut [u8]) {
+        let gain = 1.0 + (self.regs[1] & 0x1F) as f32 / 16.0;
+        let exposure = self.exposure() as f32 / 0x0800 as f32;
+        let is_edge = (self.regs[1] & 0x60) != 0;
+        let edge_ratio = EDGE_RATIO[(self.regs[4] >> 4) as usize & 0x07];
+        let invert = (self.regs[4] & 0x08) != 0;
+
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let mut value = self.sensor(x, y);
+                if is_edge {
+                    let neighbours = self.sensor(x.saturating_sub(1), y) + self.sensor(x + 1, y)
+                        + self.sensor(x, y.saturating_sub(1)) + self.sensor(x, y + 1);
+                    value += (4.0*value - neighbours) * edge_ratio / 4.0;
+                }
+                let value = (value * exposure * gain).clamp(0.0, 255.0) as u8;
+
+                let matrix = 6 + 3*((y & 0x03)*4 + (x & 0x03));
+                let mut color = if value < self.regs[matrix] {
+                    3
+                }else if value < self.regs[matrix + 1] {
+                    2
+                }else if value < self.regs[matrix + 2] {
+                    1
+                }else {
+                    0
+                };
+                if invert {
+                    color ^= 3;
+                }
+
+                let tile = (y / 8)*(WIDTH / 8) + x / 8;
+                let offset = IMAGE_OFFSET + tile*16 + (y & 0x07)*2;
+                let bit = 0x80 >> (x & 0x07);
+                for (plane, mask) in [(0, 1), (1, 2)] {
+                    if color & mask != 0 {
+                        sram[offset + plane] |= bit;
+                    }else {
+                        sram[offset + plane] &= !bit;
+                    }
+                }
+            }
+        }
+        self.frame += 1;
+    }
+}
+
+// Scrolling gradient with a checkerboard and a circle so motion and contrast are visible
+pub fn test_pattern(x: usize, y: usize, frame: usize) -> u8 {
+    let (dx, dy) = (x as i32 - 64, y as i32 - 56);
+    if dx*dx + dy*dy < 24*24 {
+        return if ((x + frame) / 4 + y / 4) & 1 == 0 {0x20} else {0xE0};
+    }
+    if y >= 96 {
+        return if ((x + frame) / 8) & 1 == 0 {0x00} else {0xFF};
+    }
+    (((x + frame) % WIDTH) * 255 / (WIDTH - 1)) as u8
+}
+
+// Returns width, height and 8 bit grayscale pixels
+fn parse_pnm(data: &[u8]) -> Option<(usize, usize, Vec<u8>)> {
+    let magic = data.get(0..2)?;
+    let mut pos = 2;
+    let mut fields = Vec::new();
+    // width, height and maxval, skipping whitespace and comments
+    while fields.len() < 3 {
+        match *data.get(pos)? {
+            b'#' => while *data.get(pos)? != b'\n' {pos += 1},
+            c if c.is_ascii_whitespace() => pos += 1,
+            _ => {
+                let start = pos;
+                while data.get(pos)?.is_ascii_digit() {pos += 1}
+                fields.push(std::str::from_utf8(&data[start..pos]).ok()?.parse::<usize>().ok()?);
+            }
+        }
+    }
+    let (width, height, maxval) = (fields[0], fields[1], fields[2].max(1));
+    let channels = match magic {
+        b"P2" | b"P5" => 1,
+        b"P3" | b"P6" => 3,
+        _ => return None,
+    };
+    let samples: Vec<usize> = if magic == b"P5" || magic == b"P6" {
+        // A single whitespace separates the header from 8 bit binary samples
+        data.get(pos + 1..pos + 1 + width*height*channels)?.iter().map(|&b| b as usize).collect()
+    }else {
+        std::str::from_utf8(&data[pos..]).ok()?.split_ascii_whitespace()
+            .take(width*height*channels)
+            .map(|s| s.parse().ok())
+            .collect::<Option<_>>()?
+    };
+    if width == 0 || height == 0 || samples.len() < width*height*channels {
+        return None;
+    }
+    let pixels = samples.chunks_exact(channels)
+        .map(|px| (px.iter().sum::<usize>() / channels * 255 / maxval).min(255) as u8)
+        .collect();
+    Some((width, height, pixels))
+}
+
+// The source image is not machine state
+savestate_fields!(Camera {regs, capture_timer, frame});
@@ -1,5 +1,21 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
 use std::time::SystemTime;
-#[derive(Debug)]
+
+use crate::eeprom::Eeprom;
+use crate::huc3::Huc3Rtc;
+use crate::camera::Camera;
+use crate::cheats::GenieCode;
+use crate::savestate::{Savestate, StateReader, StateWriter};
+
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+#[derive(Debug, Clone)]
 pub enum Mbc{
     RomOnly,
     Mbc1{
@@ -7,11 +23,26 @@ pub enum Mbc{
         is_ram_enable: bool,
         rom_bank_lo: u8,
         rom_bank_hi: u8,
+        is_multicart: bool, // MBC1M, bank_hi is wired to rom address bit 18 instead of 19
     },
     Mbc2{
         is_ram_enable: bool,
         rom_bank: u8,
     },
+    Mmm01{
+        is_ram_enable: bool,
+        is_locked: bool, // starts unlocked in the menu, bit 6 of a 0000-1FFF write maps the selected game
+        bank_mode: bool,
+        is_mode_locked: bool,
+        is_multiplex: bool, // swaps the rom bank mid bits with the ram bank low bits
+        rom_bank_lo: u8,
+        rom_bank_mid: u8,
+        rom_bank_hi: u8,
+        rom_bank_mask: u8, // set bits keep rom bank bits 1-4 from being written
+        ram_bank_lo: u8,
+        ram_bank_hi: u8,
+        ram_bank_mask: u8,
+    },
     Mbc3{
         is_enable: bool,
         rtcs: u8,
@@ -28,25 +59,73 @@ pub enum Mbc{
         rom_bank_lo: u8,
         rom_bank_hi: u8,
         ram_bank: u8,
+        has_rumble: bool, // types 0x1C-0x1E, bit 3 of the ram bank register drives the motor
+    },
+    Mbc7{
+        is_ram_enable1: bool,
+        is_ram_enable2: bool,
+        rom_bank: u8,
+        accel_x: u16,
+        accel_y: u16,
+        eeprom: Eeprom,
+    },
+    Huc1{
+        is_ir_mode: bool,
+        rom_bank: u8,
+        ram_bank: u8,
+        ir_led: bool,
+    },
+    Huc3{
+        mode: u8,
+        rom_bank: u8,
+        ram_bank: u8,
+        ir_led: bool,
+        rtc: Huc3Rtc,
+    },
+    Camera{
+        is_ram_enable: bool, // only gates writes, ram can always be read
+        rom_bank: u8,
+        ram_bank: u8,
+        is_reg_mode: bool, // ram bank bit 4 maps the sensor registers to A000-BFFF
+        camera: Camera,
+    },
+}
+
+// Called with the new motor state whenever a rumble cartridge turns it on or off
+pub struct RumbleCallback(pub Box<dyn FnMut(bool)>);
+
+impl std::fmt::Debug for RumbleCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RumbleCallback")
     }
 }
 #[derive(Debug)]
 pub struct Cartridge {
     pub rom: Vec<u8>,
+    pub header: usize, // offset of the cartridge header, 0 except for MMM01 collections
     pub bootrom: Vec<u8>,
     pub sram: Vec<u8>,
     pub romsize: usize,
     pub rombank: usize,
+    pub filebanks: usize, // banks in the rom file, the rom is padded up to rombank after it
     pub ramsize: usize,
     pub rambank: usize,
     pub mbc: Mbc,
+    pub rom_bases: [usize; 2], // rom offsets mapped at 0000-3FFF and 4000-7FFF
+    pub ram_base: Option<usize>, // sram offset mapped at A000-BFFF, None if disabled or mapped to registers
+    pub ram_mask: usize,
+    pub genie: Vec<GenieCode>, // active Game Genie codes, set by Cheats::apply
+    pub save_path: Option<PathBuf>,
+    pub rumble: bool,
+    pub on_rumble: Option<RumbleCallback>,
+    pub tilt: (f32, f32), // MBC7 accelerometer input in g, x positive to the right, y positive towards the player
 }
 
 impl Cartridge {
-    pub fn new(rom: Vec<u8>, bootrom: Vec<u8>) -> Self {
-        let romsize = 32768 << rom[0x0148];
-        let rombank = romsize / 16384;
-        let ramsize = match rom[0x0149] {
+    pub fn new(mut rom: Vec<u8>, bootrom: Vec<u8>) -> Self {
+        let header = header_offset(&rom);
+        let romsize = 32768 << rom[header + 0x0148];
+        let ramsize = match rom[header + 0x0149] {
             0x00 | 0x01 => 0,
             0x02 => 8192,
             0x03 => 32768,
@@ -54,128 +133,166 @@ impl Cartridge {
             0x05 => 65536,
             _ => panic!("ERROR: Unknown ram size at cartridge initialization"),
         };
-        let rambank = ramsize / 8192;
-        let mbc = match rom[0x0147] {
-            0x00 => Mbc::RomOnly,
-            0x01..=0x03 => Mbc::Mbc1{bank_mode: false, is_ram_enable: false, rom_bank_lo: 0x01, rom_bank_hi: 0x00},
-            0x05 | 0x06 => Mbc::Mbc2{is_ram_enable: false, rom_bank: 0x01},
-            0x0F..=0x13 => Mbc::Mbc3{
-                is_enable: false,
-                rtcs: get_rtc_s(),
-                rtcm: get_rtc_m(),
-                rtch: get_rtc_h(),
-                rtcdh: get_rtc_dh(),
-                rtcdl: get_rtc_dl(),
-                rom_bank: 0x01,
-                ram_or_rtc: 0x00,
-                latched: 0xFF,
-            },
-            0x19..=0x1E => Mbc::Mbc5{is_ram_enable: false, rom_bank_hi: 0x00, rom_bank_lo: 0x00, ram_bank: 0x00},
-            _ => panic!("Unknown / Unsupported MBC at cartridge initialization"),
+        let mbc = new_mbc(&rom, header);
+        // MMM01 headers can describe the menu rather than the collection around it and overdumps or
+        // trimmed dumps don't match the header either, its banks are counted from the file instead.
+        // The file is padded with open bus up to a power of two so banks past its end can be mapped
+        let filebanks = rom.len().div_ceil(16384);
+        let rombank = match mbc {
+            Mbc::Mmm01{..} => filebanks.next_power_of_two(),
+            _ => romsize / 16384,
         };
-        if let Mbc::Mbc2{is_ram_enable: _, rom_bank: _} = mbc {
-            let _ramsize = 512;
+        if let Mbc::Mmm01{..} = mbc {
+            rom.resize(rombank * 16384, 0xFF);
         }
+        // MBC2 has 512 half bytes of ram built in, stored one nibble per byte,
+        // MBC7 keeps the 256 bytes of its eeprom in sram
+        let ramsize = match mbc {
+            Mbc::Mbc2{..} => 512,
+            Mbc::Mbc7{..} => 256,
+            _ => ramsize,
+        };
+        let rambank = ramsize / 8192;
         let sram = vec![0u8; ramsize];
-        Cartridge{
+        let mut cart = Cartridge{
             rom,
+            header,
             bootrom,
             romsize,
             rombank,
+            filebanks,
             ramsize,
             rambank,
             sram,
             mbc,
-        }
+            rom_bases: [0, 0x4000],
+            ram_base: None,
+            ram_mask: ramsize.saturating_sub(1) & 0x1FFF,
+            genie: Vec::new(),
+            save_path: None,
+            rumble: false,
+            on_rumble: None,
+            tilt: (0.0, 0.0),
+        };
+        cart.update_banks();
+        cart
     }
     
+    // Rom reads and plain sram reads are a single indexed load from the offsets cached by update_banks
     pub fn readu8(&mut self, addr: u16) -> u8 {
         // println!("Cart read => {:04x}", addr);
+        match addr {
+            0x0000..=0x7FFF => {
+                let val = self.rom[self.rom_bases[addr as usize >> 14] | (addr as usize & 0x3FFF)];
+                if self.genie.is_empty() {val} else {self.read_genie(addr, val)}
+            }
+            0xA000..=0xBFFF => match self.ram_base {
+                Some(base) => self.sram[base | (addr as usize & self.ram_mask)],
+                None => self.read_ram(addr),
+            },
+            _ => panic!("Should be unreachable cartridge read reached at {:x}", addr),
+        }
+    }
+
+    // Writes go to the same offset reads come from so both agree in every banking mode
+    fn write_sram(&mut self, addr: u16, val: u8) {
+        if let Some(base) = self.ram_base {
+            self.sram[base | (addr as usize & self.ram_mask)] = val;
+        }
+    }
+
+    fn read_genie(&self, addr: u16, val: u8) -> u8 {
+        self.genie.iter()
+            .find(|code| code.addr == addr && code.compare.is_none_or(|compare| compare == val))
+            .map_or(val, |code| code.value)
+    }
+
+    // A000-BFFF when it is disabled or mapped to something other than sram
+    fn read_ram(&self, addr: u16) -> u8 {
         match self.mbc {
-            Mbc::RomOnly => {
-                match addr {
-                    0x0000..=0x7FFF => self.rom[addr as usize],
-                    0xA000..=0xBFFF => self.sram[(addr & 0x1FFF) as usize],
-                    _ => panic!("Should be unreachable cartridge read reached at {:x}", addr),
+            Mbc::Mbc2{is_ram_enable: true, ..} => self.sram[addr as usize & 0x01FF] | 0xF0,
+            Mbc::Mbc3{is_enable: true, rtcs, rtcm, rtch, rtcdl, rtcdh, ram_or_rtc, ..} => {
+                match ram_or_rtc {
+                    0x00..=0x03 => 0xFF, // no ram
+                    0x08 => rtcs,
+                    0x09 => rtcm,
+                    0x0A => rtch,
+                    0x0B => rtcdl,
+                    0x0C => rtcdh,
+                    _ => panic!("Read at undefined Mbc2 ram bank number {:x}", ram_or_rtc)
                 }
             }
-            Mbc::Mbc1{bank_mode, is_ram_enable, rom_bank_lo, rom_bank_hi} => {
-                match addr {
-                    0x0000..=0x3FFF => {
-                        if !bank_mode || self.romsize < 524288{
-                            self.rom[addr as usize]
-                        }else {
-                            self.rom[((rom_bank_hi as usize & 0x03) << 19) | addr as usize]
-                        }
-                    }
-                    0x4000..=0x7FFF => {
-                        self.rom[
-                            ((rom_bank_hi as usize & 0x03) << 19)
-                            |((rom_bank_lo as usize & 0x1F) << 14)
-                            |(addr as usize & 0x3FFF)
-                            & !(self.romsize)
-                            ]
-                    } 
-                    0xA000..=0xBFFF if !is_ram_enable => 0xFF,
-                    0xA000..=0xBFFF if self.romsize <= 524_288 => {
-                        if !bank_mode {
-                            self.sram[(addr & 0x1FFF) as usize]
-                        } else {
-                            self.sram[((rom_bank_hi as usize & 0x03) << 13) | (addr & 0x1FFF) as usize]
-                        }
-                    }
-                    0xA000..=0xBFFF => self.sram[(addr & 0x1FFF) as usize],
-                    _ => panic!("Should be unreachable cartridge read reached at {:x}", addr),
+            Mbc::Mbc7{is_ram_enable1: true, is_ram_enable2: true, accel_x, accel_y, eeprom, ..} if addr <= 0xAFFF => {
+                match addr & 0x00F0 {
+                    0x20 => accel_x as u8,
+                    0x30 => (accel_x >> 8) as u8,
+                    0x40 => accel_y as u8,
+                    0x50 => (accel_y >> 8) as u8,
+                    0x60 => 0x00,
+                    0x80 => eeprom.read(),
+                    _ => 0xFF,
                 }
             }
-            Mbc::Mbc2{is_ram_enable, rom_bank} => {
-                match addr {
-                    0x0000..=0x3FFF => self.rom[addr as usize],
-                    0x4000..=0x7FFF => self.rom[((rom_bank as usize & 0x0F) << 14)|(addr as usize & 0x3FFF)],
-                    0xA000..=0xBFFF if !is_ram_enable => 0xFF,
-                    0xA000..=0xBFFF => self.sram[addr as usize & 0x01FF] & 0x0F,
-                    _ => panic!("Should be unreachable cartridge read reached at {:x}", addr),
-                }
+            // No light is ever received
+            Mbc::Huc1{is_ir_mode: true, ..} => 0xC0,
+            Mbc::Huc3{mode, ref rtc, ..} => match mode {
+                0x0C => rtc.read_result(),
+                0x0D => 0x01, // commands complete instantly
+                0x0E => 0xC0,
+                _ => 0xFF,
+            },
+            Mbc::Camera{is_reg_mode: true, ref camera, ..} => camera.read_reg(addr),
+            _ => 0xFF,
+        }
+    }
+
+    // Recomputes the rom offsets mapped at 0000-3FFF / 4000-7FFF and the sram offset mapped at A000-BFFF
+    // from the mapper registers, must be called whenever they change
+    pub fn update_banks(&mut self) {
+        // rom bank 0, rom bank 1, ram bank if A000-BFFF is readable sram
+        let (rom0, rom1, ram) = match self.mbc {
+            Mbc::RomOnly => (0, 1, Some(0)),
+            Mbc::Mbc1{bank_mode, is_ram_enable, rom_bank_lo, rom_bank_hi, is_multicart} => {
+                let (hi_shift, lo_mask) = if is_multicart {(4, 0x0F)} else {(5, 0x1F)};
+                let hi = (rom_bank_hi as usize) << hi_shift;
+                let rom0 = if bank_mode {hi} else {0};
+                let ram = if bank_mode {rom_bank_hi as usize} else {0};
+                (rom0, hi | (rom_bank_lo as usize & lo_mask), Some(ram).filter(|_| is_ram_enable))
             }
-            Mbc::Mbc3{is_enable,rtcs, rtcm, rtch, rtcdl, rtcdh, rom_bank, ram_or_rtc, latched:_} => {
-                match addr {
-                    0x0000..=0x3FFF => self.rom[addr as usize],
-                    0x4000..=0x7FFF => {
-                        // println!("Cart read at {:6X}", ((rom_bank as usize & 0x7F) << 14)|(addr as usize & 0x3FFF));
-                        self.rom[((rom_bank as usize & 0x7F) << 14)|(addr as usize & 0x3FFF)]
-                    }
-                    0xA000..=0xBFFF if !is_enable => 0xFF, 
-                    0xA000..=0xBFFF => {
-                        match ram_or_rtc {
-                            0x00..=0x03 => self.sram[((ram_or_rtc as usize & 0x03) << 13)|(addr as usize & 0x1FFF)],
-                            0x08 => rtcs,
-                            0x09 => rtcm,
-                            0x0A => rtch,
-                            0x0B => rtcdl,
-                            0x0C => rtcdh,
-                            _ => panic!("Read at undefined Mbc2 ram bank number {:x}", ram_or_rtc)
-                        }
-                    }
-                    _ => panic!("Should be unreachable cartridge write reached at {:x}", addr),
-                }
+            Mbc::Mbc2{rom_bank, ..} => (0, rom_bank as usize, None),
+            Mbc::Mmm01{is_locked: false, ..} => {
+                // The menu runs from the last 32KiB of the file until a game is locked in
+                (self.filebanks.saturating_sub(2), self.filebanks.saturating_sub(1), None)
             }
-            Mbc::Mbc5{is_ram_enable, rom_bank_hi, rom_bank_lo, ram_bank} => {
-                match addr {
-                    0x0000..=0x3FFF => self.rom[addr as usize],
-                    0x4000..=0x7FFF => {
-                        self.rom[
-                            ((rom_bank_hi as usize & 0x01) << 22)
-                            |((rom_bank_lo as usize) << 14)
-                            |(addr as usize & 0x3FFF)
-                            ]
-                    }
-                    0xA000..=0xBFFF if !is_ram_enable => 0xFF,
-                    0xA000..=0xBFFF => self.sram[((ram_bank as usize & 0x0F) << 13)|(addr as usize & 0x1FFF)],
-                    _ => panic!("Should be unreachable cartridge read reached at {:x}", addr),
-                }
+            Mbc::Mmm01{is_ram_enable, bank_mode, is_multiplex, rom_bank_lo, rom_bank_mid, rom_bank_hi, rom_bank_mask, ram_bank_lo, ram_bank_hi, ..} => {
+                let (mid, ram_lo) = if is_multiplex {(ram_bank_lo, rom_bank_mid)} else {(rom_bank_mid, ram_bank_lo)};
+                let mask = (rom_bank_mask as usize) << 1;
+                let hi = ((rom_bank_hi as usize) << 7) | ((mid as usize) << 5);
+                let rom0 = if is_multiplex && bank_mode {(rom_bank_hi as usize) << 7} else {hi};
+                // Like MBC1 the unmasked bank bits select bank 1 instead of 0
+                let lo = rom_bank_lo as usize;
+                let lo = if lo & !mask & 0x1F == 0 {lo | 0x01} else {lo};
+                let ram = ((ram_bank_hi as usize) << 2) | ram_lo as usize;
+                (rom0 | (lo & mask), hi | lo, Some(ram).filter(|_| is_ram_enable))
             }
-
-        }
+            Mbc::Mbc3{is_enable, rom_bank, ram_or_rtc, ..} => {
+                (0, rom_bank as usize & 0x7F, Some(ram_or_rtc as usize).filter(|&bank| is_enable && bank <= 0x03))
+            }
+            // Unlike MBC1, bank 0 can be mapped at 4000-7FFF
+            Mbc::Mbc5{is_ram_enable, rom_bank_lo, rom_bank_hi, ram_bank, ..} => {
+                let bank = ((rom_bank_hi as usize & 0x01) << 8) | rom_bank_lo as usize;
+                (0, bank, Some(ram_bank as usize).filter(|_| is_ram_enable))
+            }
+            Mbc::Mbc7{rom_bank, ..} => (0, rom_bank as usize, None),
+            Mbc::Huc1{is_ir_mode, rom_bank, ram_bank, ..} => (0, rom_bank as usize, Some(ram_bank as usize).filter(|_| !is_ir_mode)),
+            Mbc::Huc3{mode, rom_bank, ram_bank, ..} => {
+                (0, rom_bank as usize, Some(ram_bank as usize).filter(|_| mode == 0x00 || mode == 0x0A))
+            }
+            // Camera ram can always be read, the enable only gates writes
+            Mbc::Camera{rom_bank, ram_bank, is_reg_mode, ..} => (0, rom_bank as usize, Some(ram_bank as usize).filter(|_| !is_reg_mode)),
+        };
+        self.rom_bases = [self.rom_offset(rom0, 0), self.rom_offset(rom1, 0)];
+        self.ram_base = ram.filter(|_| self.ramsize != 0).map(|bank| self.ram_offset(bank, 0));
     }
 
     pub fn writeu8(&mut self, addr: u16, val: u8) {
@@ -187,36 +304,67 @@ impl Cartridge {
                     _ => panic!("Should be unreachable cartridge write reached at {:x}", addr),
                 }
             }
-            Mbc::Mbc1{bank_mode, is_ram_enable, rom_bank_lo, rom_bank_hi} => {
+            Mbc::Mbc1{bank_mode, is_ram_enable, rom_bank_lo, rom_bank_hi, is_multicart: _} => {
                 match addr{
-                    0x0000..=0x1FFF if val & 0x0F == 0x0A => *is_ram_enable = true,
-                    0x0000..=0x1FFF => *is_ram_enable = false,
-                    0x2000..=0x3FFF if val == 0x00 => *rom_bank_lo = 0x01,
+                    0x0000..=0x1FFF => *is_ram_enable = val & 0x0F == 0x0A,
+                    0x2000..=0x3FFF if val & 0x1F == 0x00 => *rom_bank_lo = 0x01,
                     0x2000..=0x3FFF => *rom_bank_lo = val & 0x1F,
                     0x4000..=0x5FFF => *rom_bank_hi = val & 0x03,
-                    0x6000..=0x7FFF => *bank_mode = val != 0,
-                    0xA000..=0xBFFF if !*is_ram_enable => (),
-                    0xA000..=0xBFFF if self.romsize <= 524_288 => {
-                        if !*bank_mode {
-                            self.sram[(addr & 0x1FFF) as usize] = val;
-                        }else {
-                            self.sram[((*rom_bank_hi as usize & 0x03) << 13)| (addr & 0x1FFF) as usize] = val;
-                        }
-                    }
-                    0xA000..=0xBFFF => self.sram[(addr & 0x1FFF) as usize] = val,
+                    0x6000..=0x7FFF => *bank_mode = val & 0x01 != 0,
+                    0xA000..=0xBFFF => self.write_sram(addr, val),
                     _ => panic!("Should be unreachable cartridge write reached at {:x}", addr),
                 }
             }
             Mbc::Mbc2{is_ram_enable, rom_bank} => {
                 match addr {
-                    0x0000..=0x3FFF if addr & 0x0100 == 0 => if val != 0x0A {*is_ram_enable = false;} else {*is_ram_enable = true;},
-                    0x0000..=0x3FFF if val == 0x00 => *rom_bank = 0x01,
+                    // Address bit 8 selects between the ram enable and rom bank registers
+                    0x0000..=0x3FFF if addr & 0x0100 == 0 => *is_ram_enable = val & 0x0F == 0x0A,
+                    0x0000..=0x3FFF if val & 0x0F == 0x00 => *rom_bank = 0x01,
                     0x0000..=0x3FFF => *rom_bank = val & 0x0F,
+                    0x4000..=0x7FFF => (),
                     0xA000..=0xBFFF if !*is_ram_enable => (),
                     0xA000..=0xBFFF => self.sram[addr as usize & 0x01FF] = val & 0x0F,
                     _ => panic!("Should be unreachable cartridge write reached at {:x}", addr),
                 }
             }
+            Mbc::Mmm01{is_ram_enable, is_locked, bank_mode, is_mode_locked, is_multiplex, rom_bank_lo, rom_bank_mid, rom_bank_hi, rom_bank_mask, ram_bank_lo, ram_bank_hi, ram_bank_mask} => {
+                // Bits outside of the low rom / ram bank and mode can only be written before the lock
+                match addr {
+                    0x0000..=0x1FFF => {
+                        *is_ram_enable = val & 0x0F == 0x0A;
+                        if !*is_locked {
+                            *ram_bank_mask = (val >> 4) & 0x03;
+                            *is_locked = (val & 0x40) != 0;
+                        }
+                    }
+                    0x2000..=0x3FFF => {
+                        if !*is_locked {
+                            *rom_bank_mid = (val >> 5) & 0x03;
+                        }
+                        let mask = *rom_bank_mask << 1;
+                        *rom_bank_lo = (*rom_bank_lo & mask) | (val & !mask & 0x1F);
+                    }
+                    0x4000..=0x5FFF => {
+                        *ram_bank_lo = (*ram_bank_lo & *ram_bank_mask) | (val & !*ram_bank_mask & 0x03);
+                        if !*is_locked {
+                            *ram_bank_hi = (val >> 2) & 0x03;
+                            *rom_bank_hi = (val >> 4) & 0x03;
+                            *is_mode_locked = (val & 0x40) != 0;
+                        }
+                    }
+                    0x6000..=0x7FFF => {
+                        if !*is_mode_locked {
+                            *bank_mode = (val & 0x01) != 0;
+                        }
+                        if !*is_locked {
+                            *rom_bank_mask = (val >> 2) & 0x0F;
+                            *is_multiplex = (val & 0x40) != 0;
+                        }
+                    }
+                    0xA000..=0xBFFF => self.write_sram(addr, val),
+                    _ => panic!("Should be unreachable cartridge write reached at {:x}", addr),
+                }
+            }
             Mbc::Mbc3{is_enable, rtcs, rtcm, rtch, rtcdl, rtcdh, rom_bank, ram_or_rtc, latched} => {
                 match addr {
                     0x0000..=0x1FFF if val == 0x0A => *is_enable = true,
@@ -239,7 +387,7 @@ impl Cartridge {
                     0xA000..=0xBFFF if !*is_enable => (),
                     0xA000..=0xBFFF => {
                         match *ram_or_rtc {
-                            0x00..=0x03 => self.sram[((*ram_or_rtc as usize & 0x03) << 13)|(addr as usize & 0x1FFF)] = val,
+                            0x00..=0x03 => self.write_sram(addr, val),
                             0x08 => *rtcs = val,
                             0x09 => *rtcm = val,
                             0x0A => *rtch = val,
@@ -251,25 +399,306 @@ impl Cartridge {
                     _ => panic!("Should be unreachable cartridge write reached at {:x}", addr),
                 }
             }
-            Mbc::Mbc5{is_ram_enable, rom_bank_hi, rom_bank_lo, ram_bank} => {
+            Mbc::Mbc5{is_ram_enable, rom_bank_hi, rom_bank_lo, ram_bank, has_rumble} => {
                 match addr {
                     0x0000..=0x1FFF if val == 0x0A => *is_ram_enable = true,
                     0x0000..=0x1FFF => *is_ram_enable = false,
                     0x2000..=0x2FFF => *rom_bank_lo = val,
                     0x3000..=0x3FFF => *rom_bank_hi = val & 0x01,
+                    0x4000..=0x5FFF if *has_rumble => {
+                        *ram_bank = val & 0x07;
+                        let rumble = (val & 0x08) != 0;
+                        if rumble != self.rumble {
+                            self.rumble = rumble;
+                            if let Some(RumbleCallback(callback)) = &mut self.on_rumble {
+                                callback(rumble);
+                            }
+                        }
+                    }
                     0x4000..=0x5FFF => *ram_bank = val & 0x0F,
                     0x6000..=0x7FFF => (),
-                    0xA000..=0xBFFF if !*is_ram_enable => (),
-                    0xA000..=0xBFFF => self.sram[((*ram_bank as usize & 0x0F) << 13)|(addr as usize & 0x1FFF)] = val,
+                    0xA000..=0xBFFF => self.write_sram(addr, val),
                     _ => panic!("Should be unreachable cartridge write reached at {:x}", addr),
                 }
             }
+            Mbc::Mbc7{is_ram_enable1, is_ram_enable2, rom_bank, accel_x, accel_y, eeprom} => {
+                match addr {
+                    0x0000..=0x1FFF => *is_ram_enable1 = val == 0x0A,
+                    0x2000..=0x3FFF => *rom_bank = val & 0x7F,
+                    0x4000..=0x5FFF => *is_ram_enable2 = val == 0x40,
+                    0x6000..=0x7FFF => (),
+                    0xA000..=0xAFFF if *is_ram_enable1 && *is_ram_enable2 => {
+                        match addr & 0x00F0 {
+                            // Writing 0x55 then 0xAA latches the accelerometer, 0x81D0 is level and 0x70 is about 1g
+                            0x00 if val == 0x55 => {
+                                *accel_x = 0x8000;
+                                *accel_y = 0x8000;
+                            }
+                            0x10 if val == 0xAA && *accel_x == 0x8000 && *accel_y == 0x8000 => {
+                                *accel_x = (0x81D0 as f32 + 0x70 as f32 * self.tilt.0.clamp(-2.0, 2.0)) as u16;
+                                *accel_y = (0x81D0 as f32 - 0x70 as f32 * self.tilt.1.clamp(-2.0, 2.0)) as u16;
+                            }
+                            0x80 => eeprom.write(val, &mut self.sram),
+                            _ => (),
+                        }
+                    }
+                    0xA000..=0xBFFF => (),
+                    _ => panic!("Should be unreachable cartridge write reached at {:x}", addr),
+                }
+            }
+            Mbc::Huc1{is_ir_mode, rom_bank, ram_bank, ir_led} => {
+                match addr {
+                    0x0000..=0x1FFF => *is_ir_mode = val & 0x0F == 0x0E,
+                    0x2000..=0x3FFF if val & 0x3F == 0x00 => *rom_bank = 0x01,
+                    0x2000..=0x3FFF => *rom_bank = val & 0x3F,
+                    0x4000..=0x5FFF => *ram_bank = val & 0x03,
+                    0x6000..=0x7FFF => (),
+                    0xA000..=0xBFFF if *is_ir_mode => *ir_led = (val & 0x01) != 0,
+                    0xA000..=0xBFFF => self.write_sram(addr, val),
+                    _ => panic!("Should be unreachable cartridge write reached at {:x}", addr),
+                }
+            }
+            Mbc::Huc3{mode, rom_bank, ram_bank, ir_led, rtc} => {
+                match addr {
+                    // 0x0 ram read only, 0xA ram read/write, 0xB rtc command, 0xC rtc result, 0xD rtc semaphore, 0xE infrared
+                    0x0000..=0x1FFF => *mode = val & 0x0F,
+                    0x2000..=0x3FFF => *rom_bank = val & 0x7F,
+                    0x4000..=0x5FFF => *ram_bank = val & 0x03,
+                    0x6000..=0x7FFF => (),
+                    0xA000..=0xBFFF => match *mode {
+                        0x0A => self.write_sram(addr, val),
+                        0x0B => rtc.write_command(val),
+                        0x0E => *ir_led = (val & 0x01) != 0,
+                        _ => (),
+                    },
+                    _ => panic!("Should be unreachable cartridge write reached at {:x}", addr),
+                }
+            }
+            Mbc::Camera{is_ram_enable, rom_bank, ram_bank, is_reg_mode, camera} => {
+                match addr {
+                    0x0000..=0x1FFF => *is_ram_enable = val & 0x0F == 0x0A,
+                    0x2000..=0x3FFF => *rom_bank = val & 0x3F,
+                    0x4000..=0x5FFF => {
+                        *is_reg_mode = (val & 0x10) != 0;
+                        *ram_bank = val & 0x0F;
+                    }
+                    0x6000..=0x7FFF => (),
+                    0xA000..=0xBFFF if *is_reg_mode => camera.write_reg(addr, val),
+                    0xA000..=0xBFFF if *is_ram_enable => self.write_sram(addr, val),
+                    0xA000..=0xBFFF => (),
+                    _ => panic!("Should be unreachable cartridge write reached at {:x}", addr),
+                }
+            }
+        }
+        if addr <= 0x7FFF {
+            self.update_banks();
         }
     }
+    // Banks past the end of the rom / ram mirror the ones below, sizes are always powers of two
+    pub fn rom_offset(&self, bank: usize, addr: u16) -> usize {
+        ((bank & (self.rombank - 1)) << 14) | (addr as usize & 0x3FFF)
+    }
+
+    pub fn ram_offset(&self, bank: usize, addr: u16) -> usize {
+        ((bank << 13) | (addr as usize & 0x1FFF)) & (self.ramsize - 1)
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(self.rom[self.header + 0x0147], 0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFC | 0xFE | 0xFF)
+    }
+
+    // Loads the battery backed ram from save_path if there is one, a missing file is not an error
+    pub fn load_save(&mut self) -> io::Result<()> {
+        let path = match &self.save_path {
+            Some(path) if self.has_battery() => path,
+            _ => return Ok(()),
+        };
+        match fs::read(path) {
+            Ok(data) => {
+                let len = data.len().min(self.sram.len());
+                self.sram[..len].copy_from_slice(&data[..len]);
+                // The HuC3 clock is stored after the ram
+                if let Mbc::Huc3{rtc, ..} = &mut self.mbc {
+                    rtc.load_data(&data[len..]);
+                }
+                Ok(())
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn write_save(&self) -> io::Result<()> {
+        match (&self.save_path, &self.mbc) {
+            (Some(path), Mbc::Huc3{rtc, ..}) => {
+                let mut data = self.sram.clone();
+                data.extend_from_slice(&rtc.save_data());
+                fs::write(path, data)
+            }
+            (Some(path), _) if self.has_battery() && !self.sram.is_empty() => fs::write(path, &self.sram),
+            _ => Ok(()),
+        }
+    }
+
+    // Clocks mapper hardware that runs on its own, called once per cpu step
+    pub fn tick(&mut self, tstates: usize) {
+        if let Mbc::Camera{camera, ..} = &mut self.mbc {
+            if camera.tick(tstates) {
+                camera.capture(&mut self.sram);
+            }
+        }
+    }
+
+    pub fn is_tone_active(&self) -> bool {
+        matches!(&self.mbc, Mbc::Huc3{rtc, ..} if rtc.is_tone)
+    }
+
     pub fn read_bootrom(&mut self, addr: u16) -> u8 {
         self.bootrom[addr as usize & 0xFF]
     }
+
+    // Puts the mapper back in its power on state, sram and the clock of a HuC3 are kept
+    // and the camera keeps the image it was given
+    pub fn reset(&mut self) {
+        let mut mbc = new_mbc(&self.rom, self.header);
+        match (&mut mbc, &mut self.mbc) {
+            (Mbc::Huc3{rtc, ..}, Mbc::Huc3{rtc: old, ..}) => {
+                rtc.memory = std::mem::take(&mut old.memory);
+                rtc.base_time = old.base_time;
+            }
+            (Mbc::Camera{camera, ..}, Mbc::Camera{camera: old, ..}) => camera.image = old.image.take(),
+            _ => (),
+        }
+        self.mbc = mbc;
+        if self.rumble {
+            self.rumble = false;
+            if let Some(on_rumble) = &mut self.on_rumble {
+                (on_rumble.0)(false);
+            }
+        }
+        self.update_banks();
+    }
+
+    // Same mapper and ram size without the rom or anything outside the machine state,
+    // states are read into one of these first to check them
+    pub fn scratch(&self) -> Self {
+        Cartridge{
+            rom: Vec::new(),
+            header: self.header,
+            bootrom: Vec::new(),
+            sram: vec![0; self.sram.len()],
+            romsize: self.romsize,
+            rombank: self.rombank,
+            filebanks: self.filebanks,
+            ramsize: self.ramsize,
+            rambank: self.rambank,
+            mbc: self.mbc.clone(),
+            rom_bases: self.rom_bases,
+            ram_base: self.ram_base,
+            ram_mask: self.ram_mask,
+            genie: Vec::new(),
+            save_path: None,
+            rumble: self.rumble,
+            on_rumble: None,
+            tilt: self.tilt,
+        }
+    }
+}
+
+// Mapper registers as they are at power on
+pub fn new_mbc(rom: &[u8], header: usize) -> Mbc {
+    match rom[header + 0x0147] {
+        0x00 => Mbc::RomOnly,
+        0x01..=0x03 => Mbc::Mbc1{
+            bank_mode: false,
+            is_ram_enable: false,
+            rom_bank_lo: 0x01,
+            rom_bank_hi: 0x00,
+            is_multicart: is_mbc1_multicart(rom),
+        },
+        0x05 | 0x06 => Mbc::Mbc2{is_ram_enable: false, rom_bank: 0x01},
+        0x0B..=0x0D => Mbc::Mmm01{
+            is_ram_enable: false,
+            is_locked: false,
+            bank_mode: false,
+            is_mode_locked: false,
+            is_multiplex: false,
+            rom_bank_lo: 0x00,
+            rom_bank_mid: 0x00,
+            rom_bank_hi: 0x00,
+            rom_bank_mask: 0x00,
+            ram_bank_lo: 0x00,
+            ram_bank_hi: 0x00,
+            ram_bank_mask: 0x00,
+        },
+        0x0F..=0x13 => Mbc::Mbc3{
+            is_enable: false,
+            rtcs: get_rtc_s(),
+            rtcm: get_rtc_m(),
+            rtch: get_rtc_h(),
+            rtcdh: get_rtc_dh(),
+            rtcdl: get_rtc_dl(),
+            rom_bank: 0x01,
+            ram_or_rtc: 0x00,
+            latched: 0xFF,
+        },
+        0x19..=0x1E => Mbc::Mbc5{
+            is_ram_enable: false,
+            rom_bank_hi: 0x00,
+            rom_bank_lo: 0x01,
+            ram_bank: 0x00,
+            has_rumble: rom[0x0147] >= 0x1C,
+        },
+        0x22 => Mbc::Mbc7{
+            is_ram_enable1: false,
+            is_ram_enable2: false,
+            rom_bank: 0x01,
+            accel_x: 0x8000,
+            accel_y: 0x8000,
+            eeprom: Eeprom::new(),
+        },
+        0xFC => Mbc::Camera{is_ram_enable: false, rom_bank: 0x01, ram_bank: 0x00, is_reg_mode: false, camera: Camera::new()},
+        0xFE => Mbc::Huc3{mode: 0x00, rom_bank: 0x01, ram_bank: 0x00, ir_led: false, rtc: Huc3Rtc::new()},
+        0xFF => Mbc::Huc1{is_ir_mode: false, rom_bank: 0x01, ram_bank: 0x00, ir_led: false},
+        _ => panic!("Unknown / Unsupported MBC at cartridge initialization"),
+    }
+}
+
+// MMM01 collections boot the menu stored in the last 32KiB, so that is where their header is
+pub fn header_offset(rom: &[u8]) -> usize {
+    let header = rom.len().saturating_sub(0x8000);
+    if header > 0 && matches!(rom[header + 0x0147], 0x0B..=0x0D) && rom[header + 0x0104..header + 0x0134] == NINTENDO_LOGO {
+        header
+    }else {
+        0
+    }
+}
+
+// Header title without the trailing padding and cgb flag, empty if the rom is too small to have a header
+pub fn rom_title(rom: &[u8]) -> String {
+    if rom.len() < 0x0150 {
+        return String::new();
+    }
+    let header = header_offset(rom);
+    let title = &rom[header + 0x0134..header + 0x0144];
+    let len = title.iter().position(|&c| c == 0 || c >= 0x80).unwrap_or(title.len());
+    String::from_utf8_lossy(&title[..len]).trim().to_string()
+}
+
+// Big endian sum of the rom stored at 014E-014F
+pub fn rom_checksum(rom: &[u8]) -> Option<u16> {
+    if rom.len() < 0x0150 {
+        return None;
+    }
+    let header = header_offset(rom);
+    Some(u16::from_be_bytes([rom[header + 0x014E], rom[header + 0x014F]]))
+}
+
+// MBC1M multicarts are 8 Mbit and repeat the header of each 2 Mbit game at the start of bank 0x10
+pub fn is_mbc1_multicart(rom: &[u8]) -> bool {
+    rom.len() == 0x100000 && rom[0x40104..0x40134] == NINTENDO_LOGO
 }
+
 pub fn secs_since_epoch() -> u64 {
     SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
 }
@@ -287,4 +716,87 @@ pub fn get_rtc_dl() -> u8 {
 }
 pub fn get_rtc_dh() -> u8 {
     ((((secs_since_epoch() / 86400) as f64 % 365.2425) as u16 & 0x0100) >> 8) as u8
+}
+
+// Saves the registers of the mbc, the variant itself comes from the rom so it is not saved
+macro_rules! savestate_mbc {
+    ($($variant:ident {$($field:ident),*}),* $(,)?) => {
+        impl Savestate for Mbc {
+            fn save(&self, w: &mut StateWriter) {
+                match self {
+                    $(Mbc::$variant{$($field,)* ..} => {$($field.save(w);)*})*
+                }
+            }
+            fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+                match self {
+                    $(Mbc::$variant{$($field,)* ..} => {$($field.load(r)?;)*})*
+                }
+                Ok(())
+            }
+        }
+    };
+}
+
+savestate_mbc!(
+    RomOnly {},
+    Mbc1 {bank_mode, is_ram_enable, rom_bank_lo, rom_bank_hi},
+    Mbc2 {is_ram_enable, rom_bank},
+    Mmm01 {
+        is_ram_enable, is_locked, bank_mode, is_mode_locked, is_multiplex,
+        rom_bank_lo, rom_bank_mid, rom_bank_hi, rom_bank_mask, ram_bank_lo, ram_bank_hi, ram_bank_mask
+    },
+    Mbc3 {is_enable, rtcs, rtcm, rtch, rtcdl, rtcdh, rom_bank, ram_or_rtc, latched},
+    Mbc5 {is_ram_enable, rom_bank_lo, rom_bank_hi, ram_bank},
+    Mbc7 {is_ram_enable1, is_ram_enable2, rom_bank, accel_x, accel_y, eeprom},
+    Huc1 {is_ir_mode, rom_bank, ram_bank, ir_led},
+    Huc3 {mode, rom_bank, ram_bank, ir_led, rtc},
+    Camera {is_ram_enable, rom_bank, ram_bank, is_reg_mode, camera},
+);
+
+impl Savestate for Cartridge {
+    fn save(&self, w: &mut StateWriter) {
+        self.sram.save(w);
+        self.mbc.save(w);
+        self.rumble.save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let sram_len = self.sram.len();
+        self.sram.load(r)?;
+        if self.sram.len() != sram_len {
+            return Err("state is for a cartridge with a different ram size".to_string());
+        }
+        self.mbc.load(r)?;
+        let rumble = self.rumble;
+        self.rumble.load(r)?;
+        if self.rumble != rumble {
+            if let Some(on_rumble) = &mut self.on_rumble {
+                (on_rumble.0)(self.rumble);
+            }
+        }
+        self.update_banks();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mmm01_menu_in_a_file_of_five_banks() {
+        let mut rom = vec![0; 5 * 0x4000];
+        for bank in 0..5 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        let header = 0xC000;
+        rom[header + 0x0104..header + 0x0134].copy_from_slice(&NINTENDO_LOGO);
+        rom[header + 0x0147] = 0x0B;
+        let mut cart = Cartridge::new(rom, Vec::new());
+        assert_eq!(cart.rombank, 8);
+        assert_eq!(cart.rom.len(), 8 * 0x4000);
+        assert_eq!((cart.readu8(0x0000), cart.readu8(0x4000)), (3, 4));
+
+        // Banks past the end of the file read as open bus
+        assert_eq!(cart.rom[cart.rom_offset(7, 0)], 0xFF);
+    }
 }
\ No newline at end of file
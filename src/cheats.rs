@@ -0,0 +1,229 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::bus::Bus;
+use crate::savestate::{savestate_fields, Savestate, StateReader, StateWriter};
+
+// Game Genie codes replace rom reads, the optional compare byte limits them to one rom bank
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GenieCode {
+    pub addr: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    GameGenie(GenieCode),
+    // GameShark codes write ram once per frame. The bank byte is usually 01 for whatever is mapped,
+    // 8X / 9X name bank X which is honoured for sram and only bank 0 / 1 exist for wram on DMG
+    GameShark{
+        bank: u8,
+        addr: u16,
+        value: u8,
+    },
+}
+
+impl Default for Code {
+    fn default() -> Self {
+        Code::GameGenie(GenieCode::default())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Cheat {
+    pub code: Code,
+    pub text: String,
+    pub name: String,
+    pub enabled: bool,
+}
+
+// Cheats are kept apart from the machine so they never end up in snapshots of it,
+// the cartridge only holds a copy of the active Game Genie codes.
+#[derive(Debug)]
+pub struct Cheats {
+    pub list: Vec<Cheat>,
+    pub enabled: bool,
+    pub locked: bool, // set while a movie is active, the movie fixes which cheats run
+}
+
+impl Default for Cheats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats{
+            list: Vec::new(),
+            enabled: true,
+            locked: false,
+        }
+    }
+
+    // All enabled, used for the cheats stored in a movie
+    pub fn from_codes(codes: &[String]) -> Result<Self, String> {
+        let mut cheats = Cheats::new();
+        for code in codes {
+            cheats.add(code, "")?;
+        }
+        Ok(cheats)
+    }
+
+    // One cheat per line, "CODE description", lines starting with '-' are loaded disabled and '#' starts a comment
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut cheats = Cheats::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (enabled, line) = match line.strip_prefix('-') {
+                Some(rest) => (false, rest.trim_start()),
+                None => (true, line.strip_prefix('+').unwrap_or(line).trim_start()),
+            };
+            let (code, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            cheats.add(code, name.trim()).map_err(|err| format!("line {}: {}", i + 1, err))?;
+            cheats.list.last_mut().unwrap().enabled = enabled;
+        }
+        Ok(cheats)
+    }
+
+    // A missing file is an empty list
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Cheats::parse(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Cheats::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn add(&mut self, text: &str, name: &str) -> Result<(), String> {
+        let code = parse_code(text)?;
+        self.list.push(Cheat{
+            code,
+            text: text.to_uppercase(),
+            name: name.to_string(),
+            enabled: true,
+        });
+        Ok(())
+    }
+
+    pub fn toggle(&mut self, index: usize) {
+        if let Some(cheat) = self.list.get_mut(index) {
+            cheat.enabled ^= true;
+        }
+    }
+
+    fn active(&self) -> impl Iterator<Item = &Cheat> {
+        self.list.iter().filter(move |cheat| self.enabled && cheat.enabled)
+    }
+
+    pub fn active_codes(&self) -> Vec<String> {
+        self.active().map(|cheat| cheat.text.clone()).collect()
+    }
+
+    // Hands the active Game Genie codes to the cartridge, must be called after the list or enables change
+    pub fn apply(&self, bus: &mut Bus) {
+        bus.cart.genie = self.active().filter_map(|cheat| match cheat.code {
+            Code::GameGenie(genie) => Some(genie),
+            _ => None,
+        }).collect();
+    }
+
+    // Called once per frame, parse_code only lets through addresses in ram
+    pub fn write_ram(&self, bus: &mut Bus) {
+        for cheat in self.active() {
+            if let Code::GameShark{bank, addr, value} = cheat.code {
+                match addr {
+                    // Written straight into the named bank whether it is mapped or the ram is enabled
+                    0xA000..=0xBFFF if bank >= 0x80 => {
+                        if bus.cart.ramsize != 0 {
+                            let offset = bus.cart.ram_offset((bank & 0x0F) as usize, addr);
+                            bus.cart.sram[offset] = value;
+                        }
+                    }
+                    _ => bus.writeu8(addr, value),
+                }
+            }
+        }
+    }
+
+    pub fn print(&self) {
+        println!("Cheats {}", if self.enabled {"on"} else {"off"});
+        for (i, cheat) in self.list.iter().enumerate() {
+            println!("  {:>2} [{}] {} {}", i + 1, if cheat.enabled {'x'} else {' '}, cheat.text, cheat.name);
+        }
+    }
+}
+
+// "ABC-DEF-GHI" / "ABC-DEF" Game Genie or "TTVVLLHH" GameShark
+pub fn parse_code(text: &str) -> Result<Code, String> {
+    let digits: Vec<u8> = text.chars()
+        .filter(|&c| c != '-')
+        .map(|c| c.to_digit(16).map(|d| d as u8))
+        .collect::<Option<_>>()
+        .ok_or(format!("invalid cheat code {}", text))?;
+    let byte = |i: usize| (digits[i] << 4) | digits[i + 1];
+    match digits.len() {
+        6 | 9 => {
+            // Address nibbles are stored as CDE then F with F inverted
+            let addr = ((digits[5] as u16 ^ 0x0F) << 12) | ((digits[2] as u16) << 8) | ((digits[3] as u16) << 4) | digits[4] as u16;
+            if addr >= 0x8000 {
+                return Err(format!("Game Genie code {} is outside of the rom", text));
+            }
+            // Compare byte is G and I rotated left by 2 after xor with 0xBA, H is unused
+            let compare = (digits.len() == 9).then(|| ((digits[6] << 4) | digits[8]).rotate_right(2) ^ 0xBA);
+            Ok(Code::GameGenie(GenieCode{addr, value: byte(0), compare}))
+        }
+        8 => {
+            let addr = u16::from_le_bytes([byte(4), byte(6)]);
+            // Sram, wram and hram, writing io registers every frame would trigger dma, sound and so on
+            if !matches!(addr, 0xA000..=0xDFFF | 0xFF80..=0xFFFE) {
+                return Err(format!("GameShark code {} is outside of ram", text));
+            }
+            if byte(0) >= 0x80 && (byte(0) & 0x0F) > 1 && (0xD000..=0xDFFF).contains(&addr) {
+                return Err(format!("GameShark code {} needs Game Boy Color wram bank {}", text, byte(0) & 0x0F));
+            }
+            Ok(Code::GameShark{bank: byte(0), addr, value: byte(2)})
+        }
+        _ => Err(format!("invalid cheat code {}", text)),
+    }
+}
+
+// Only the text is saved, the code is parsed again when the cheat is loaded
+impl Savestate for Cheat {
+    fn save(&self, w: &mut StateWriter) {
+        self.text.save(w);
+        self.name.save(w);
+        self.enabled.save(w);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.text.load(r)?;
+        self.name.load(r)?;
+        self.enabled.load(r)?;
+        self.code = parse_code(&self.text)?;
+        Ok(())
+    }
+}
+
+savestate_fields!(Cheats {list, enabled});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gameshark_addresses() {
+        assert_eq!(parse_code("01FF34C0"), Ok(Code::GameShark{bank: 0x01, addr: 0xC034, value: 0xFF}));
+        assert_eq!(parse_code("821200A0"), Ok(Code::GameShark{bank: 0x82, addr: 0xA000, value: 0x12}));
+        assert!(parse_code("010180FF").is_ok());
+        // Vram, echo ram, oam, io registers and IE
+        for code in ["01FF0080", "01FF00E0", "01FF00FE", "018046FF", "010140FF", "010026FF", "0101FFFF"] {
+            assert!(parse_code(code).is_err(), "{}", code);
+        }
+        assert!(parse_code("8201FFD0").is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::apu::SAMPLE_RATE;
+use crate::ini::{self, Section};
+use crate::ppu::GRAY_PALETTE;
+
+// Settings from config.ini, the unnamed section at the top holds the defaults and
+// "[title NAME]" / "[checksum XXXX]" sections override them for the rom with that header title
+// or global checksum. [keyboard] and [controller] sections are handed to Controls.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub scale: u32, // window size in multiples of 160x144
+    pub palette: [[u8; 3]; 4], // rgb of the four shades, lightest first
+    pub boot_rom: PathBuf,
+    pub skip_boot: bool,
+    pub audio_rate: u32,
+    pub save_dir: Option<PathBuf>, // battery saves and states go next to the rom when not set
+    pub state_cheats: bool, // the cheat list is saved in states and rewind snapshots and restored with them
+    pub controls: Vec<Section>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Config{
+            scale: 4,
+            palette: GRAY_PALETTE,
+            boot_rom: PathBuf::from("dmg_boot.bin"),
+            skip_boot: false,
+            audio_rate: SAMPLE_RATE as u32,
+            save_dir: None,
+            state_cheats: false,
+            controls: Vec::new(),
+        }
+    }
+
+    // $XDG_CONFIG_HOME/quarrygb/config.ini, ~/.config/quarrygb when it isn't set or %APPDATA%\quarrygb on windows
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .or_else(|| env::var_os("APPDATA").map(PathBuf::from))?;
+        Some(dir.join("quarrygb").join("config.ini"))
+    }
+
+    // A missing file leaves the defaults, relative paths in it are relative to the file
+    pub fn load(path: &Path, title: &str, checksum: Option<u16>) -> Result<Self, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Config::new()),
+            Err(err) => return Err(format!("{}: {}", path.display(), err)),
+        };
+        let sections = ini::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut config = Config::new();
+        config.apply(&sections, dir, title, checksum).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Ok(config)
+    }
+
+    pub fn apply(&mut self, sections: &[Section], dir: &Path, title: &str, checksum: Option<u16>) -> Result<(), String> {
+        let is_game = |name: &str| match name.split_once(' ') {
+            Some(("title", name)) => name.trim() == title,
+            Some(("checksum", name)) => u16::from_str_radix(name.trim(), 16).ok() == checksum && checksum.is_some(),
+            _ => false,
+        };
+        for section in sections {
+            match section.name.as_str() {
+                "keyboard" | "controller" => self.controls.push(section.clone()),
+                name if name.is_empty() || is_game(name) => {
+                    for entry in &section.entries {
+                        self.set(&entry.key, &entry.value, dir).map_err(|err| format!("line {}: {}", entry.line, err))?;
+                    }
+                }
+                name if name.starts_with("title ") || name.starts_with("checksum ") => (),
+                name => return Err(format!("unknown section [{}]", name)),
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, key: &str, value: &str, dir: &Path) -> Result<(), String> {
+        let invalid = || format!("invalid {} {}", key, value);
+        match key {
+            "scale" => self.scale = value.parse().ok().filter(|&scale| scale > 0).ok_or_else(invalid)?,
+            "palette" => {
+                // Four rrggbb colors, lightest first
+                let colors: Vec<&str> = value.split(',').map(|color| color.trim().trim_start_matches('#')).collect();
+                if colors.len() != 4 {
+                    return Err(invalid());
+                }
+                for (shade, color) in self.palette.iter_mut().zip(colors) {
+                    let rgb = u32::from_str_radix(color, 16).ok().filter(|_| color.len() == 6).ok_or_else(invalid)?;
+                    *shade = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+                }
+            }
+            "boot_rom" => self.boot_rom = dir.join(value),
+            "skip_boot" => self.skip_boot = value.parse().map_err(|_| invalid())?,
+            "audio_rate" => self.audio_rate = value.parse().ok().filter(|rate| (8000..=192000).contains(rate)).ok_or_else(invalid)?,
+            "save_dir" => self.save_dir = (!value.is_empty()).then(|| dir.join(value)),
+            "state_cheats" => self.state_cheats = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("unknown setting {}", key)),
+        }
+        Ok(())
+    }
+
+    // Where the file for the rom at base_path with this extension is kept
+    pub fn save_file(&self, base_path: &Path, extension: &str) -> PathBuf {
+        let path = base_path.with_extension(extension);
+        match (&self.save_dir, path.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => path,
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use sdl2::controller::{Axis, Button};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+
+use crate::ini::{self, Section};
+
+// Game Boy buttons, the value is the bit in Bus::joypad
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Joypad {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hotkey {
+    FastForward,
+    Uncapped,
+    SlowMotion,
+    Rewind,
+    SaveState,
+    LoadState,
+    MuteCh1,
+    MuteCh2,
+    MuteCh3,
+    MuteCh4,
+    Mute,
+    Pause,
+    FrameAdvance,
+    Reset,
+    HardReset,
+    Quit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Joypad(Joypad),
+    Hotkey(Hotkey),
+}
+
+// A key, controller button or direction of a controller axis
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Input {
+    Key(Keycode),
+    Button(Button),
+    Axis(Axis, bool),
+}
+
+// Names used in the controls file
+const ACTIONS: [(&str, Action); 24] = [
+    ("right", Action::Joypad(Joypad::Right)),
+    ("left", Action::Joypad(Joypad::Left)),
+    ("up", Action::Joypad(Joypad::Up)),
+    ("down", Action::Joypad(Joypad::Down)),
+    ("a", Action::Joypad(Joypad::A)),
+    ("b", Action::Joypad(Joypad::B)),
+    ("select", Action::Joypad(Joypad::Select)),
+    ("start", Action::Joypad(Joypad::Start)),
+    ("fast_forward", Action::Hotkey(Hotkey::FastForward)),
+    ("uncapped", Action::Hotkey(Hotkey::Uncapped)),
+    ("slow_motion", Action::Hotkey(Hotkey::SlowMotion)),
+    ("rewind", Action::Hotkey(Hotkey::Rewind)),
+    ("save_state", Action::Hotkey(Hotkey::SaveState)),
+    ("load_state", Action::Hotkey(Hotkey::LoadState)),
+    ("mute_ch1", Action::Hotkey(Hotkey::MuteCh1)),
+    ("mute_ch2", Action::Hotkey(Hotkey::MuteCh2)),
+    ("mute_ch3", Action::Hotkey(Hotkey::MuteCh3)),
+    ("mute_ch4", Action::Hotkey(Hotkey::MuteCh4)),
+    ("mute", Action::Hotkey(Hotkey::Mute)),
+    ("pause", Action::Hotkey(Hotkey::Pause)),
+    ("frame_advance", Action::Hotkey(Hotkey::FrameAdvance)),
+    ("reset", Action::Hotkey(Hotkey::Reset)),
+    ("hard_reset", Action::Hotkey(Hotkey::HardReset)),
+    ("quit", Action::Hotkey(Hotkey::Quit)),
+];
+
+const DEFAULT_KEYS: [(Keycode, Action); 24] = [
+    (Keycode::Right, Action::Joypad(Joypad::Right)),
+    (Keycode::Left, Action::Joypad(Joypad::Left)),
+    (Keycode::Up, Action::Joypad(Joypad::Up)),
+    (Keycode::Down, Action::Joypad(Joypad::Down)),
+    (Keycode::Z, Action::Joypad(Joypad::A)),
+    (Keycode::X, Action::Joypad(Joypad::B)),
+    (Keycode::Backspace, Action::Joypad(Joypad::Select)),
+    (Keycode::Return, Action::Joypad(Joypad::Start)),
+    (Keycode::Tab, Action::Hotkey(Hotkey::FastForward)),
+    (Keycode::F3, Action::Hotkey(Hotkey::Uncapped)),
+    (Keycode::F4, Action::Hotkey(Hotkey::SlowMotion)),
+    (Keycode::R, Action::Hotkey(Hotkey::Rewind)),
+    (Keycode::F1, Action::Hotkey(Hotkey::SaveState)),
+    (Keycode::F12, Action::Hotkey(Hotkey::LoadState)),
+    (Keycode::U, Action::Hotkey(Hotkey::MuteCh1)),
+    (Keycode::I, Action::Hotkey(Hotkey::MuteCh2)),
+    (Keycode::O, Action::Hotkey(Hotkey::MuteCh3)),
+    (Keycode::P, Action::Hotkey(Hotkey::MuteCh4)),
+    (Keycode::M, Action::Hotkey(Hotkey::Mute)),
+    (Keycode::Space, Action::Hotkey(Hotkey::Pause)),
+    (Keycode::N, Action::Hotkey(Hotkey::FrameAdvance)),
+    (Keycode::Home, Action::Hotkey(Hotkey::Reset)),
+    (Keycode::End, Action::Hotkey(Hotkey::HardReset)),
+    (Keycode::Escape, Action::Hotkey(Hotkey::Quit)),
+];
+
+// SDL names buttons by position on an Xbox pad, A is the bottom one and B the right one
+const DEFAULT_BUTTONS: [(Button, Action); 9] = [
+    (Button::DPadRight, Action::Joypad(Joypad::Right)),
+    (Button::DPadLeft, Action::Joypad(Joypad::Left)),
+    (Button::DPadUp, Action::Joypad(Joypad::Up)),
+    (Button::DPadDown, Action::Joypad(Joypad::Down)),
+    (Button::B, Action::Joypad(Joypad::A)),
+    (Button::A, Action::Joypad(Joypad::B)),
+    (Button::Back, Action::Joypad(Joypad::Select)),
+    (Button::Start, Action::Joypad(Joypad::Start)),
+    (Button::Guide, Action::Hotkey(Hotkey::Pause)),
+];
+
+const DEFAULT_AXES: [((Axis, bool), Action); 6] = [
+    ((Axis::LeftX, true), Action::Joypad(Joypad::Right)),
+    ((Axis::LeftX, false), Action::Joypad(Joypad::Left)),
+    ((Axis::LeftY, false), Action::Joypad(Joypad::Up)),
+    ((Axis::LeftY, true), Action::Joypad(Joypad::Down)),
+    ((Axis::TriggerRight, true), Action::Hotkey(Hotkey::FastForward)),
+    ((Axis::TriggerLeft, true), Action::Hotkey(Hotkey::Rewind)),
+];
+
+// Axes count as pressed past PRESS and released again below RELEASE
+const AXIS_PRESS: i16 = 16384;
+const AXIS_RELEASE: i16 = 8192;
+
+// Maps keys and game controller buttons / axes to actions. "leftx-" / "leftx+" are the two
+// directions of an axis, triggers only go positive so "triggerleft" is the same as "triggerleft+".
+#[derive(Debug)]
+pub struct Controls {
+    pub keys: HashMap<Keycode, Action>,
+    pub buttons: HashMap<Button, Action>,
+    pub axes: HashMap<(Axis, bool), Action>,
+    pub axes_pressed: HashMap<(Axis, bool), bool>,
+    pub held: HashMap<Input, Action>, // inputs held down and the action each one pressed
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Controls {
+    pub fn new() -> Self {
+        Controls{
+            keys: HashMap::from(DEFAULT_KEYS),
+            buttons: HashMap::from(DEFAULT_BUTTONS),
+            axes: HashMap::from(DEFAULT_AXES),
+            axes_pressed: HashMap::new(),
+            held: HashMap::new(),
+        }
+    }
+
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let sections = ini::parse(&text).map_err(|err| format!("{}: {}", path.display(), err))?;
+        self.apply(&sections).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    // Actions listed in the [keyboard] and [controller] sections replace their default bindings,
+    // an empty value leaves the action unbound
+    pub fn apply(&mut self, sections: &[Section]) -> Result<(), String> {
+        for section in sections {
+            let is_keyboard = match section.name.as_str() {
+                "keyboard" => true,
+                "controller" => false,
+                _ => continue,
+            };
+            for entry in &section.entries {
+                let action = action(&entry.key).ok_or(format!("line {}: unknown action {}", entry.line, entry.key))?;
+                let result = if is_keyboard {
+                    self.keys.retain(|_, bound| *bound != action);
+                    self.bind_keys(action, &entry.value)
+                }else {
+                    self.buttons.retain(|_, bound| *bound != action);
+                    self.axes.retain(|_, bound| *bound != action);
+                    self.bind_controller(action, &entry.value)
+                };
+                result.map_err(|err| format!("line {}: {}", entry.line, err))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn bind_keys(&mut self, action: Action, keys: &str) -> Result<(), String> {
+        for name in split_list(keys) {
+            let key = Keycode::from_name(name).ok_or(format!("unknown key {}", name))?;
+            self.keys.insert(key, action);
+        }
+        Ok(())
+    }
+
+    fn bind_controller(&mut self, action: Action, inputs: &str) -> Result<(), String> {
+        for name in split_list(inputs) {
+            if let Some(button) = Button::from_string(name) {
+                self.buttons.insert(button, action);
+                continue;
+            }
+            let (axis, is_positive) = match name.strip_suffix('-') {
+                Some(axis) => (axis, false),
+                None => (name.strip_suffix('+').unwrap_or(name), true),
+            };
+            let axis = Axis::from_string(axis).ok_or(format!("unknown controller button or axis {}", name))?;
+            self.axes.insert((axis, is_positive), action);
+        }
+        Ok(())
+    }
+
+    // The left stick tilts MBC7 cartridges, so it can't move the d-pad as well
+    pub fn unbind_axes(&mut self, axes: &[Axis]) {
+        self.axes.retain(|(axis, _), _| !axes.contains(axis));
+    }
+
+    // The bound actions and whether they were pressed or released, key repeats are ignored
+    pub fn actions(&mut self, event: &Event) -> Vec<(Action, bool)> {
+        let inputs = match event {
+            Event::KeyDown {keycode: Some(key), repeat: false, ..} => vec![(Input::Key(*key), true)],
+            Event::KeyUp {keycode: Some(key), ..} => vec![(Input::Key(*key), false)],
+            Event::ControllerButtonDown {button, ..} => vec![(Input::Button(*button), true)],
+            Event::ControllerButtonUp {button, ..} => vec![(Input::Button(*button), false)],
+            Event::ControllerAxisMotion {axis, value, ..} => self.axis_inputs(*axis, *value),
+            _ => Vec::new(),
+        };
+        inputs.into_iter().filter_map(|(input, pressed)| self.update(input, pressed)).collect()
+    }
+
+    fn bound(&self, input: Input) -> Option<Action> {
+        match input {
+            Input::Key(key) => self.keys.get(&key),
+            Input::Button(button) => self.buttons.get(&button),
+            Input::Axis(axis, is_positive) => self.axes.get(&(axis, is_positive)),
+        }.copied()
+    }
+
+    // An action stays pressed while any input bound to it is held, so with a key and a pad button
+    // on the same action releasing one of them doesn't release the action
+    fn update(&mut self, input: Input, pressed: bool) -> Option<(Action, bool)> {
+        let action = if pressed {
+            let action = self.bound(input)?;
+            if self.held.insert(input, action).is_some() {
+                return None;
+            }
+            action
+        }else {
+            self.held.remove(&input)?
+        };
+        let holding = self.held.values().filter(|&&held| held == action).count();
+        (holding == pressed as usize).then_some((action, pressed))
+    }
+
+    // A stick flicked across the center releases one direction and presses the other in the same event
+    fn axis_inputs(&mut self, axis: Axis, value: i16) -> Vec<(Input, bool)> {
+        let magnitude = value.saturating_abs();
+        let mut inputs = Vec::new();
+        for is_positive in [value < 0, value >= 0] {
+            let direction = (axis, is_positive);
+            let was_pressed = self.axes_pressed.get(&direction).copied().unwrap_or(false);
+            let is_pressed = is_positive == (value >= 0) && magnitude >= if was_pressed {AXIS_RELEASE} else {AXIS_PRESS};
+            if is_pressed != was_pressed {
+                self.axes_pressed.insert(direction, is_pressed);
+                inputs.push((Input::Axis(axis, is_positive), is_pressed));
+            }
+        }
+        inputs
+    }
+}
+
+pub fn action(name: &str) -> Option<Action> {
+    ACTIONS.iter().find(|(action, _)| *action == name).map(|&(_, action)| action)
+}
+
+fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(',').map(str::trim).filter(|name| !name.is_empty())
+}
+
+// Joypad state in the Bus::joypad format with one button changed
+pub fn set_button(state: u8, button: Joypad, pressed: bool) -> u8 {
+    let bit = 1 << button as u8;
+    if pressed {state | bit} else {state & !bit}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sdl2::keyboard::Mod;
+
+    fn key(keycode: Keycode, pressed: bool) -> Event {
+        if pressed {
+            Event::KeyDown {timestamp: 0, window_id: 0, keycode: Some(keycode), scancode: None, keymod: Mod::NOMOD, repeat: false}
+        }else {
+            Event::KeyUp {timestamp: 0, window_id: 0, keycode: Some(keycode), scancode: None, keymod: Mod::NOMOD, repeat: false}
+        }
+    }
+
+    fn button(button: Button, pressed: bool) -> Event {
+        if pressed {
+            Event::ControllerButtonDown {timestamp: 0, which: 0, button}
+        }else {
+            Event::ControllerButtonUp {timestamp: 0, which: 0, button}
+        }
+    }
+
+    fn axis(axis: Axis, value: i16) -> Event {
+        Event::ControllerAxisMotion {timestamp: 0, which: 0, axis, value}
+    }
+
+    const A: Action = Action::Joypad(Joypad::A);
+    const LEFT: Action = Action::Joypad(Joypad::Left);
+
+    #[test]
+    fn two_inputs_on_one_button() {
+        let mut controls = Controls::new();
+        assert_eq!(controls.actions(&key(Keycode::Z, true)), [(A, true)]);
+        assert!(controls.actions(&button(Button::B, true)).is_empty());
+        assert!(controls.actions(&key(Keycode::Z, false)).is_empty());
+        assert_eq!(controls.actions(&button(Button::B, false)), [(A, false)]);
+
+        // D-pad button and stick on the same direction
+        assert_eq!(controls.actions(&button(Button::DPadLeft, true)), [(LEFT, true)]);
+        assert!(controls.actions(&axis(Axis::LeftX, -30000)).is_empty());
+        assert!(controls.actions(&button(Button::DPadLeft, false)).is_empty());
+        assert_eq!(controls.actions(&axis(Axis::LeftX, 0)), [(LEFT, false)]);
+    }
+
+    #[test]
+    fn releases_without_a_press_are_ignored() {
+        let mut controls = Controls::new();
+        assert!(controls.actions(&key(Keycode::Z, false)).is_empty());
+        assert_eq!(controls.actions(&key(Keycode::Z, true)), [(A, true)]);
+        assert!(controls.actions(&key(Keycode::Z, true)).is_empty());
+        assert_eq!(controls.actions(&key(Keycode::Z, false)), [(A, false)]);
+    }
+
+    #[test]
+    fn unbound_stick_for_tilt() {
+        let mut controls = Controls::new();
+        controls.unbind_axes(&[Axis::LeftX, Axis::LeftY]);
+        assert!(controls.actions(&axis(Axis::LeftX, -30000)).is_empty());
+        assert!(controls.actions(&axis(Axis::LeftY, 30000)).is_empty());
+        assert_eq!(controls.actions(&axis(Axis::TriggerRight, 30000)), [(Action::Hotkey(Hotkey::FastForward), true)]);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::bus::Bus;
+use crate::savestate::savestate_fields;
 pub struct Cpu{
     pub a: u8,
     pub b: u8,
@@ -64,10 +65,21 @@ impl Cpu {
 
     pub fn clock(&mut self, bus: &mut Bus) -> usize {
 
+        if bus.is_cpu_stop {
+            // Only the joypad wakes the cpu from STOP, interrupts enabled or not
+            if bus.joypad_lines() == 0x0F {
+                return 1;
+            }
+            bus.is_cpu_stop = false;
+        }
+
         if (bus.iff & bus.ie) != 0 {
             bus.is_cpu_halt = false;
             if bus.ime {
                 bus.ime = false;
+                // Two internal cycles before the pc is pushed
+                bus.cpu_idle();
+                bus.cpu_idle();
                 if bus.iff & (1 << 0) != 0 {
                     bus.iff &= !(1 << 0);
                     self.pushu16(bus, self.pc);
@@ -126,7 +138,7 @@ impl Cpu {
             0x0D => self.f.dec_r8(&mut self.c),
             0x0E => self.pc.ld_r8_imm8(bus, &mut self.c),
             0x0F => self.rrca(),
-            0x10 => panic!("Unsupported STOP instruction at {:x}", self.pc-1), //STOP inst needs support if upgrading
+            0x10 => self.stop(bus),
             0x11 => self.pc.ld_r16_imm16(bus, &mut self.d, &mut self.e),
             0x12 => self.ld_r16_indr_a(bus, self.d, self.e),
             0x13 => Cpu::inc_r16(&mut self.d, &mut self.e),
@@ -824,6 +836,7 @@ impl Cpu {
         4
     }
     pub fn push_r16(&mut self, bus: &mut Bus, r16hi: u8, r16lo: u8) -> usize {
+        bus.cpu_idle();
         self.sp-=1;
         bus.writeu8(self.sp, r16hi);
         self.sp-=1;
@@ -1020,6 +1033,7 @@ impl Cpu {
         let addrhi = bus.readu8(self.pc);
         self.pc+=1;
         if cc {
+            bus.cpu_idle();
             self.sp-=1;
             bus.writeu8(self.sp, Cpu::hi_byte(self.pc));
             self.sp-=1;
@@ -1030,6 +1044,8 @@ impl Cpu {
         return 3;
     }
     pub fn ret_cc(&mut self, bus: &mut Bus, cc: bool) -> usize {
+        // The condition is checked in an internal cycle before the pops
+        bus.cpu_idle();
         if cc {
             let pclo = bus.readu8(self.sp);
             self.sp+=1;
@@ -1049,6 +1065,7 @@ impl Cpu {
         4
     }
     pub fn rst(&mut self, bus: &mut Bus, vec: u16) -> usize {
+        bus.cpu_idle();
         self.sp = self.sp.wrapping_sub(1);
         bus.writeu8(self.sp, Cpu::hi_byte(self.pc));
         self.sp = self.sp.wrapping_sub(1);
@@ -1076,6 +1093,15 @@ impl Cpu {
         bus.is_cpu_halt = true;
         1
     }
+    // STOP skips the byte after it and resets DIV, with a button already held it doesn't stop at all
+    pub fn stop(&mut self, bus: &mut Bus) -> usize {
+        self.pc += 1;
+        bus.timer.div = 0;
+        if bus.joypad_lines() == 0x0F {
+            bus.is_cpu_stop = true;
+        }
+        1
+    }
     pub fn af(&self) -> u16 {
         Cpu::as_word(self.a, self.f)
     }
@@ -1320,3 +1346,6 @@ pub const CB_PREFIX: &[&'static str; 256] = &[
 //LD SP,[FFFFh]
 //ILLEGAL
 //LD HL,SP+r8
+
+// The debugging fields are not machine state
+savestate_fields!(Cpu {a, b, c, d, e, h, l, f, sp, pc});
\ No newline at end of file
@@ -0,0 +1,149 @@
+use std::io::{self, BufRead, Write};
+
+use crate::bus::Bus;
+use crate::cheats::Cheats;
+use crate::cpu::Cpu;
+use crate::ramsearch::{self, Filter, RamSearch, Width};
+
+const HELP: &str = "\
+c                       continue emulation
+r                       print the cpu registers
+x <addr> [len]          dump memory
+w <addr> <val>          write memory
+search new [8|16]       start a ram search over wram, hram and sram
+search <filter> [val]   keep candidates that are eq / ne val or changed / unchanged / inc / dec
+search list [n]         print the first n candidates
+cheat                   list the cheats
+cheat <n>               turn cheat n on / off
+addresses are hex, values are decimal unless prefixed with $ or 0x";
+
+// Console debugger reading commands from stdin while the emulation is paused
+#[derive(Debug, Default)]
+pub struct Debugger {
+    pub search: Option<RamSearch>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger{search: None}
+    }
+
+    // Returns when the input ends or on "c"
+    pub fn prompt(&mut self, cpu: &mut Cpu, bus: &mut Bus, cheats: &mut Cheats) {
+        println!("Paused, type help for commands");
+        let stdin = io::stdin();
+        loop {
+            print!("> ");
+            io::stdout().flush().ok();
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let line = line.trim();
+            if line == "c" || line == "continue" {
+                return;
+            }
+            match self.execute(line, cpu, bus, cheats) {
+                Ok(output) if output.is_empty() => (),
+                Ok(output) => println!("{}", output),
+                Err(err) => println!("error: {}", err),
+            }
+        }
+    }
+
+    pub fn execute(&mut self, line: &str, cpu: &mut Cpu, bus: &mut Bus, cheats: &mut Cheats) -> Result<String, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args.as_slice() {
+            [] => Ok(String::new()),
+            ["help"] => Ok(HELP.to_string()),
+            ["r"] => {
+                cpu.debug_print(bus);
+                Ok(String::new())
+            }
+            ["x", addr, rest @ ..] => {
+                let addr = parse_addr(addr)?;
+                let len = rest.first().map_or(Ok(16), |len| parse_value(len))?;
+                let mut output = String::new();
+                for row in (0..len).step_by(16) {
+                    let start = addr.wrapping_add(row as u16);
+                    output += &format!("{:04X}:", start);
+                    for i in 0..16.min(len - row) {
+                        output += &format!(" {:02X}", bus.readu8(start.wrapping_add(i as u16)));
+                    }
+                    output += "\n";
+                }
+                Ok(output.trim_end().to_string())
+            }
+            ["w", addr, val] => {
+                let val = parse_value(val)?;
+                bus.writeu8(parse_addr(addr)?, u8::try_from(val).map_err(|_| "value is not a byte")?);
+                Ok(String::new())
+            }
+            ["search", "new", rest @ ..] => {
+                let width = match rest.first() {
+                    None | Some(&"8") => Width::Byte,
+                    Some(&"16") => Width::Word,
+                    Some(width) => return Err(format!("unknown width {}", width)),
+                };
+                let search = RamSearch::new(bus, width);
+                let count = search.candidates.len();
+                self.search = Some(search);
+                Ok(format!("{} candidates", count))
+            }
+            ["search", "list", rest @ ..] => {
+                let max = rest.first().map_or(Ok(32), |max| parse_value(max))?;
+                let search = self.search.as_ref().ok_or("no search running, use search new")?;
+                let results = search.results(bus, max);
+                let mut output = format!("{} candidates", search.candidates.len());
+                for candidate in results {
+                    output += &format!("\n{:>7}  {:5} (was {})", ramsearch::describe(candidate.index), candidate.value, candidate.previous);
+                }
+                Ok(output)
+            }
+            ["search", filter, rest @ ..] => {
+                let value = || rest.first()
+                    .ok_or(format!("search {} needs a value", filter))
+                    .and_then(|val| parse_value(val))
+                    .and_then(|val| u16::try_from(val).map_err(|_| "value is too large".to_string()));
+                let filter = match *filter {
+                    "eq" => Filter::Equal(value()?),
+                    "ne" => Filter::NotEqual(value()?),
+                    "changed" => Filter::Changed,
+                    "unchanged" => Filter::Unchanged,
+                    "inc" => Filter::Increased,
+                    "dec" => Filter::Decreased,
+                    _ => return Err(format!("unknown search filter {}", filter)),
+                };
+                let search = self.search.as_mut().ok_or("no search running, use search new")?;
+                search.filter(bus, filter);
+                Ok(format!("{} candidates", search.candidates.len()))
+            }
+            ["cheat"] => {
+                cheats.print();
+                Ok(String::new())
+            }
+            ["cheat", _] if cheats.locked => Err("cheats can't change while a movie is active".to_string()),
+            ["cheat", n] => {
+                let index = parse_value(n)?.checked_sub(1).filter(|&index| index < cheats.list.len());
+                cheats.toggle(index.ok_or(format!("no cheat {}", n))?);
+                cheats.apply(bus);
+                cheats.print();
+                Ok(String::new())
+            }
+            _ => Err(format!("unknown command {}, type help for commands", line)),
+        }
+    }
+}
+
+fn parse_addr(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid address {}", text))
+}
+
+fn parse_value(text: &str) -> Result<usize, String> {
+    let hex = text.strip_prefix('$').or(text.strip_prefix("0x"));
+    match hex {
+        Some(digits) => usize::from_str_radix(digits, 16),
+        None => text.parse(),
+    }.map_err(|_| format!("invalid value {}", text))
+}
@@ -1,4 +1,5 @@
 use crate::bus::Bus;
+use crate::savestate::savestate_fields;
 pub struct Dma {
     index: u8,
 }
@@ -25,4 +26,6 @@ impl Dma {
             }       
         }
     }
-}
\ No newline at end of file
+}
+
+savestate_fields!(Dma {index});
\ No newline at end of file
@@ -0,0 +1,134 @@
+use crate::savestate::savestate_fields;
+
+// 93LC56 serial EEPROM used by MBC7, organized as 128 16-bit words.
+// The words themselves live in the cartridge sram so they are saved like regular battery ram.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Eeprom {
+    pub cs: bool,
+    pub clk: bool,
+    pub di: bool,
+    pub do_: bool,
+
+    pub command: u32,
+    pub command_bits: u8,
+
+    pub read_data: u16,
+    pub read_bits: u8,
+
+    pub is_write_enable: bool,
+}
+
+// start bit + 2 bit opcode + 8 bit address
+const COMMAND_BITS: u8 = 11;
+const DATA_COMMAND_BITS: u8 = COMMAND_BITS + 16;
+
+impl Eeprom {
+    pub fn new() -> Self {
+        Eeprom{
+            do_: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        ((self.cs as u8) << 7)|((self.clk as u8) << 6)|((self.di as u8) << 1)|(self.do_ as u8)
+    }
+
+    // Pins are written all at once, CS bit 7, CLK bit 6, DI bit 1
+    pub fn write(&mut self, val: u8, data: &mut [u8]) {
+        let cs = (val & 0x80) != 0;
+        let clk = (val & 0x40) != 0;
+        self.di = (val & 0x02) != 0;
+
+        if !cs {
+            self.command = 0;
+            self.command_bits = 0;
+            self.read_bits = 0;
+            self.do_ = true;
+        }else if !self.clk && clk {
+            self.clock(data);
+        }
+        self.cs = cs;
+        self.clk = clk;
+    }
+
+    fn clock(&mut self, data: &mut [u8]) {
+        if self.read_bits > 0 {
+            self.do_ = (self.read_data & 0x8000) != 0;
+            self.read_data <<= 1;
+            self.read_bits -= 1;
+            return;
+        }
+
+        // Leading zeros before the start bit are ignored
+        if self.command_bits == 0 && !self.di {
+            return;
+        }
+        self.command = (self.command << 1) | self.di as u32;
+        self.command_bits += 1;
+
+        if self.command_bits == COMMAND_BITS {
+            let opcode = (self.command >> 8) & 0x03;
+            let addr = (self.command & 0x7F) as usize;
+            match opcode {
+                // READ, a dummy 0 then the 16 bits of the word
+                0b10 => {
+                    self.read_data = u16::from_le_bytes([data[2*addr], data[2*addr + 1]]);
+                    self.read_bits = 16;
+                    self.do_ = false;
+                    self.finish_command();
+                }
+                // ERASE
+                0b11 => {
+                    if self.is_write_enable {
+                        data[2*addr..2*addr + 2].copy_from_slice(&[0xFF, 0xFF]);
+                    }
+                    self.finish_command();
+                }
+                // WRITE, waits for the data
+                0b01 => (),
+                _ => match (self.command >> 6) & 0x03 {
+                    0b11 => {
+                        self.is_write_enable = true;
+                        self.finish_command();
+                    }
+                    0b00 => {
+                        self.is_write_enable = false;
+                        self.finish_command();
+                    }
+                    // ERAL
+                    0b10 => {
+                        if self.is_write_enable {
+                            data.fill(0xFF);
+                        }
+                        self.finish_command();
+                    }
+                    // WRAL, waits for the data
+                    _ => (),
+                },
+            }
+        }else if self.command_bits == DATA_COMMAND_BITS {
+            let opcode = (self.command >> 24) & 0x03;
+            let addr = ((self.command >> 16) & 0x7F) as usize;
+            let word = (self.command as u16).to_le_bytes();
+            if self.is_write_enable {
+                if opcode == 0b01 {
+                    data[2*addr..2*addr + 2].copy_from_slice(&word);
+                }else {
+                    for chunk in data.chunks_exact_mut(2) {
+                        chunk.copy_from_slice(&word);
+                    }
+                }
+            }
+            self.do_ = true;
+            self.finish_command();
+        }
+    }
+
+    fn finish_command(&mut self) {
+        self.command = 0;
+        self.command_bits = 0;
+    }
+}
+
+savestate_fields!(Eeprom {cs, clk, di, do_, command, command_bits, read_data, read_bits, is_write_enable});
@@ -0,0 +1,125 @@
+use crate::bus::Bus;
+use crate::cartridge::{Cartridge, Mbc};
+use crate::cpu::Cpu;
+
+pub const HEADER_SIZE: usize = 0x70;
+
+// Driver code placed in the unused space of the synthetic rom
+const IDLE_LOOP: u16 = 0x0150;
+
+#[derive(Debug)]
+pub struct Gbs {
+    pub song_count: u8,
+    pub first_song: u8, // 1 based like the header
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub stack_ptr: u16,
+    pub tma: u8,
+    pub tac: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    pub data: Vec<u8>,
+}
+
+impl Gbs {
+    pub fn is_gbs(bytes: &[u8]) -> bool {
+        bytes.len() >= HEADER_SIZE && &bytes[0x00..0x03] == b"GBS"
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if !Gbs::is_gbs(bytes) {
+            return Err("Not a GBS file".to_string());
+        }
+        if bytes[0x03] != 1 {
+            return Err(format!("Unsupported GBS version {}", bytes[0x03]));
+        }
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let text = |offset: usize| {
+            let field = &bytes[offset..offset + 32];
+            let len = field.iter().position(|&b| b == 0).unwrap_or(32);
+            String::from_utf8_lossy(&field[..len]).into_owned()
+        };
+        let gbs = Gbs{
+            song_count: bytes[0x04],
+            first_song: bytes[0x05].max(1),
+            load_addr: word(0x06),
+            init_addr: word(0x08),
+            play_addr: word(0x0A),
+            stack_ptr: word(0x0C),
+            tma: bytes[0x0E],
+            tac: bytes[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+            data: bytes[HEADER_SIZE..].to_vec(),
+        };
+        if gbs.load_addr < 0x0400 || gbs.load_addr >= 0x8000 {
+            return Err(format!("Invalid GBS load address {:04X}", gbs.load_addr));
+        }
+        if gbs.song_count == 0 {
+            return Err("GBS file contains no songs".to_string());
+        }
+        Ok(gbs)
+    }
+
+    pub fn uses_timer(&self) -> bool {
+        (self.tac & 0x04) != 0
+    }
+
+    // Builds an MBC5 rom image with the music data at its load address and a small driver
+    // that calls the play routine from the vblank or timer interrupt.
+    pub fn cartridge(&self) -> Cartridge {
+        let needed = self.load_addr as usize + self.data.len();
+        let mut size_code = 0u8;
+        while (32768usize << size_code) < needed {
+            size_code += 1;
+        }
+        let mut rom = vec![0u8; 32768 << size_code];
+        rom[self.load_addr as usize..needed].copy_from_slice(&self.data);
+
+        // RST vectors are relocated to the load address
+        for rst in (0x00..=0x38).step_by(8) {
+            let [lo, hi] = (self.load_addr + rst).to_le_bytes();
+            rom[rst as usize..rst as usize + 3].copy_from_slice(&[0xC3, lo, hi]); // JP load+rst
+        }
+        let [lo, hi] = self.play_addr.to_le_bytes();
+        let play_vector = if self.uses_timer() {0x50} else {0x40};
+        for vector in [0x40, 0x48, 0x50, 0x58, 0x60] {
+            if vector == play_vector {
+                rom[vector..vector + 4].copy_from_slice(&[0xCD, lo, hi, 0xD9]); // CALL play, RETI
+            }else {
+                rom[vector] = 0xD9; // RETI
+            }
+        }
+        let idle = IDLE_LOOP as usize;
+        rom[idle..idle + 5].copy_from_slice(&[0xFB, 0x76, 0x00, 0x18, 0xFB]); // EI, HALT, NOP, JR -5
+
+        rom[0x0147] = 0x1A; // MBC5+RAM
+        rom[0x0148] = size_code;
+        rom[0x0149] = 0x02; // 8KiB
+
+        let mut cart = Cartridge::new(rom, Vec::new());
+        cart.mbc = Mbc::Mbc5{is_ram_enable: true, rom_bank_lo: 0x01, rom_bank_hi: 0x00, ram_bank: 0x00, has_rumble: false};
+        cart.update_banks();
+        cart
+    }
+
+    // Sets the machine up as after the boot rom and calls init with A = song (0 based),
+    // returning into the idle loop which waits for interrupts.
+    pub fn start_song(&self, cpu: &mut Cpu, bus: &mut Bus, song: u8) {
+        bus.after_bootup();
+        cpu.after_bootup();
+        bus.is_boot_rom = false;
+        bus.iff = 0x00;
+        bus.ie = if self.uses_timer() {1 << 2} else {1 << 0};
+        bus.timer.tma = self.tma;
+        bus.timer.write_tac(self.tac);
+
+        cpu.sp = self.stack_ptr;
+        cpu.pushu16(bus, IDLE_LOOP);
+        cpu.a = song;
+        cpu.pc = self.init_addr;
+    }
+}
@@ -0,0 +1,104 @@
+use crate::cartridge::secs_since_epoch;
+use crate::savestate::savestate_fields;
+
+// HuC3 real time clock, driven by nibble sized commands written in mode 0xB and read back in mode 0xC.
+// Its memory holds the minutes of the day at 0x00-0x02 and the day counter at 0x03-0x05 once latched.
+#[derive(Debug, Clone)]
+pub struct Huc3Rtc {
+    pub memory: Vec<u8>, // 0x100 nibbles, one per byte
+    pub access_index: u8,
+    pub command: u8,
+    pub result: u8,
+    pub base_time: u64, // epoch seconds at which the clock read 0 minutes / 0 days
+    pub is_tone: bool,
+}
+
+pub const SAVE_SIZE: usize = 0x100 + 8;
+
+impl Default for Huc3Rtc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Huc3Rtc {
+    pub fn new() -> Self {
+        Huc3Rtc{
+            memory: vec![0; 0x100],
+            access_index: 0,
+            command: 0,
+            result: 0,
+            base_time: secs_since_epoch(),
+            is_tone: false,
+        }
+    }
+
+    pub fn minutes_elapsed(&self) -> u64 {
+        secs_since_epoch().saturating_sub(self.base_time) / 60
+    }
+
+    pub fn read_result(&self) -> u8 {
+        0x80 | ((self.command & 0x07) << 4) | (self.result & 0x0F)
+    }
+
+    pub fn write_command(&mut self, val: u8) {
+        let arg = val & 0x0F;
+        self.command = val >> 4;
+        match self.command {
+            // Read, write and write then increment at the access index
+            0x1 => {
+                self.result = self.memory[self.access_index as usize];
+                self.access_index = self.access_index.wrapping_add(1);
+            }
+            0x2 => self.memory[self.access_index as usize] = arg,
+            0x3 => {
+                self.memory[self.access_index as usize] = arg;
+                self.access_index = self.access_index.wrapping_add(1);
+            }
+            0x4 => self.access_index = (self.access_index & 0xF0) | arg,
+            0x5 => self.access_index = (self.access_index & 0x0F) | (arg << 4),
+            0x6 => match arg {
+                0x0 => self.latch(),
+                0x1 => self.set_time(),
+                0x2 => self.result = 0x01, // status, always ready
+                0xE => self.is_tone ^= true,
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    // Copies the running clock into memory 0x00-0x05
+    pub fn latch(&mut self) {
+        let elapsed = self.minutes_elapsed();
+        let minutes = (elapsed % 1440) as u16;
+        let days = ((elapsed / 1440) & 0x0FFF) as u16;
+        for i in 0..3 {
+            self.memory[i] = ((minutes >> (4*i)) & 0x0F) as u8;
+            self.memory[3 + i] = ((days >> (4*i)) & 0x0F) as u8;
+        }
+    }
+
+    // Sets the running clock from memory 0x00-0x05
+    pub fn set_time(&mut self) {
+        let nibbles = |start: usize| (0..3).fold(0u64, |acc, i| acc | ((self.memory[start + i] as u64 & 0x0F) << (4*i)));
+        let minutes = nibbles(0) % 1440;
+        let days = nibbles(3);
+        self.base_time = secs_since_epoch().saturating_sub(60 * (minutes + 1440 * days));
+    }
+
+    pub fn save_data(&self) -> Vec<u8> {
+        let mut data = self.memory.clone();
+        data.extend_from_slice(&self.base_time.to_le_bytes());
+        data
+    }
+
+    pub fn load_data(&mut self, data: &[u8]) {
+        if data.len() >= SAVE_SIZE {
+            self.memory.copy_from_slice(&data[..0x100]);
+            self.base_time = u64::from_le_bytes(data[0x100..SAVE_SIZE].try_into().unwrap());
+        }
+    }
+}
+
+savestate_fields!(Huc3Rtc {memory, access_index, command, result, base_time, is_tone});
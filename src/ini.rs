@@ -0,0 +1,36 @@
+// Minimal ini files: "[section]" headers and "key = value" lines, '#' or ';' starts a comment line.
+// Entries before the first header go into a section with an empty name.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub line: usize,
+    pub key: String,
+    pub value: String,
+}
+
+pub fn parse(text: &str) -> Result<Vec<Section>, String> {
+    let mut sections = vec![Section{name: String::new(), entries: Vec::new()}];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name.strip_suffix(']').ok_or(format!("line {}: missing ]", i + 1))?;
+            sections.push(Section{name: name.trim().to_string(), entries: Vec::new()});
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or(format!("line {}: expected key = value", i + 1))?;
+        sections.last_mut().unwrap().entries.push(Entry{
+            line: i + 1,
+            key: key.trim().to_lowercase(),
+            value: value.trim().to_string(),
+        });
+    }
+    Ok(sections)
+}
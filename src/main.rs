@@ -3,149 +3,919 @@ pub mod cpu;
 pub mod cartridge;
 pub mod ppu;
 pub mod dma;
+pub mod eeprom;
+pub mod huc3;
+pub mod camera;
 pub mod timer;
 pub mod apu;
-use cartridge::Cartridge;
+pub mod pacer;
+pub mod options;
+pub mod wav;
+pub mod gbs;
+pub mod visualizer;
+pub mod archive;
+pub mod patch;
+pub mod cheats;
+pub mod ramsearch;
+pub mod debugger;
+pub mod movie;
+pub mod savestate;
+pub mod rewind;
+pub mod ini;
+pub mod controls;
+pub mod config;
+use cartridge::{Cartridge, Mbc, RumbleCallback};
 use bus::Bus;
 use cpu::Cpu;
 use ppu::Ppu;
 use dma::Dma;
+use pacer::{Pacer, PauseControl, Resampler, SpeedControl};
+use options::Options;
+use wav::Recorder;
+use gbs::Gbs;
+use visualizer::Visualizer;
+use cheats::Cheats;
+use debugger::Debugger;
+use movie::{Movie, Mode};
+use rewind::Rewind;
+use controls::{Controls, Action, Hotkey};
+use config::Config;
 
 
 use sdl2::pixels::{PixelFormatEnum};
-use sdl2::event::Event;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::Window;
 use sdl2::keyboard::Keycode;
+use sdl2::mouse::MouseButton;
+use sdl2::controller::{Axis, GameController};
+use sdl2::GameControllerSubsystem;
 use sdl2::audio::{AudioSpecDesired};
 
+use std::cell::RefCell;
 use std::env;
 use std::fs;
-use std::time::{Duration,Instant};
-use std::thread;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+pub const TSTATES_PER_FRAME: usize = 70224;
+// A snapshot every 4 frames for 600 snapshots keeps the last 40 seconds
+pub const REWIND_INTERVAL: u32 = 4;
+pub const REWIND_CAPACITY: usize = 600;
+
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let boot_rom = fs::read("dmg_boot.bin").unwrap();
-    let cart_rom = fs::read(&args[1]).unwrap();
-    let debugmode = if args.len() < 3 {
-        false
+    let opts = match Options::parse(&args) {
+        Ok(opts) => opts,
+        Err(err) => {
+            eprintln!("{}", err);
+            eprintln!("usage: {} <rom> [debugmode] [--wav <file>] [--stems] [--headless <frames>] [--track <n>] [--vgm <file>] [--camera <image>] [--entry <name>] [--patch <file>...] [--cheat <code>...] [--record <movie>] [--play <movie>] [--fast <x>] [--slow <x>] [--controls <file>] [--config <file>]", args[0]);
+            std::process::exit(1);
+        }
+    };
+    let mut cart_rom = match archive::read_rom(&opts.rom_path, opts.entry.as_deref()) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("Failed to load rom {}", err);
+            std::process::exit(1);
+        }
+    };
+    // Save and vgm files are named after the file given, not the rom inside an archive
+    let base_path = archive::base_path(&opts.rom_path);
+
+    let patches = if opts.patches.is_empty() {
+        patch::find_patches(&base_path)
+    }else {
+        opts.patches.iter().map(PathBuf::from).collect()
+    };
+    for path in patches {
+        cart_rom = match patch::apply_file(cart_rom, &path) {
+            Ok(rom) => rom,
+            Err(err) => {
+                eprintln!("Failed to apply patch {}", err);
+                std::process::exit(1);
+            }
+        };
+        println!("Applied patch {}", path.display());
+    }
+    let rom_crc = crc32fast::hash(&cart_rom);
+    let gbs = if Gbs::is_gbs(&cart_rom) {
+        match Gbs::parse(&cart_rom) {
+            Ok(gbs) => Some(gbs),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
     }else {
-        args[2].parse::<bool>().unwrap_or(false)
+        None
+    };
+    // 0 based index of the gbs song being played
+    let mut song = match &gbs {
+        Some(gbs) => opts.track.unwrap_or(gbs.first_song).clamp(1, gbs.song_count) - 1,
+        None => 0,
+    };
+
+    // Per-game sections of the config are matched against the header, or the title of a gbs file
+    let (title, checksum) = match &gbs {
+        Some(gbs) => (gbs.title.clone(), None),
+        None => (cartridge::rom_title(&cart_rom), cartridge::rom_checksum(&cart_rom)),
+    };
+    let config_path = opts.config_path.as_ref().map(PathBuf::from).or_else(Config::default_path);
+    let config = match &config_path {
+        Some(path) => Config::load(path, &title, checksum).unwrap_or_else(|err| {
+            eprintln!("Failed to load config {}", err);
+            std::process::exit(1);
+        }),
+        None => Config::new(),
+    };
+    if let Some(dir) = &config.save_dir {
+        if let Err(err) = fs::create_dir_all(dir) {
+            eprintln!("Failed to create save directory {}: {}", dir.display(), err);
+        }
+    }
+    let skip_boot = opts.debugmode || config.skip_boot;
+
+    let cart = match &gbs {
+        Some(gbs) => gbs.cartridge(),
+        None => {
+            // The boot rom is only needed when it runs
+            let bootrom = match fs::read(&config.boot_rom) {
+                Ok(bootrom) => bootrom,
+                Err(_) if skip_boot => Vec::new(),
+                Err(err) => {
+                    eprintln!("Failed to load boot rom {}: {}", config.boot_rom.display(), err);
+                    std::process::exit(1);
+                }
+            };
+            Cartridge::new(cart_rom, bootrom)
+        }
     };
-    let cart = Cartridge::new(cart_rom, boot_rom);
     let mut bus = Bus::new(cart);
     let mut cpu = Cpu::new();
     let mut ppu = Ppu::new();
     let mut dma = Dma::new();
+    bus.apu.sample_rate = config.audio_rate as f64;
+    ppu.palette = config.palette;
+
+    if let Some(path) = &opts.camera_image {
+        if let Mbc::Camera{camera, ..} = &mut bus.cart.mbc {
+            if let Err(err) = camera.load_image(path) {
+                eprintln!("Failed to load camera image, using the test pattern: {}", err);
+            }
+        }
+    }
+
+    // Cheats from "game.cht" next to the rom plus the ones given with --cheat
+    let cheat_path = base_path.with_extension("cht");
+    let mut cheats = Cheats::load(&cheat_path).unwrap_or_else(|err| {
+        eprintln!("Failed to load cheats from {}: {}", cheat_path.display(), err);
+        Cheats::new()
+    });
+    for code in &opts.cheats {
+        if let Err(err) = cheats.add(code, "") {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    // Movies start from power-on with blank cartridge ram, so the save file is left alone
+    let mut movie = if let Some(path) = &opts.play_path {
+        match Movie::play(Path::new(path)) {
+            Ok(movie) => {
+                movie.check(rom_crc);
+                println!("Playing movie {} ({} frames)", path, movie.frames.len());
+                Some(movie)
+            }
+            Err(err) => {
+                eprintln!("Failed to load movie {}", err);
+                std::process::exit(1);
+            }
+        }
+    }else if let Some(path) = &opts.record_path {
+        match Movie::record(Path::new(path), rom_crc, skip_boot, cheats.active_codes()) {
+            Ok(movie) => Some(movie),
+            Err(err) => {
+                eprintln!("Failed to create movie {}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+    }else {
+        None
+    };
+    let skip_boot = movie.as_ref().map_or(skip_boot, |movie| movie.skip_boot);
 
-    if debugmode {
+    // Playback runs with the cheats stored in the movie, and the list can't change while one is active
+    if let Some(movie) = &movie {
+        if movie.mode == Mode::Playing {
+            cheats = Cheats::from_codes(&movie.cheats).unwrap_or_else(|err| {
+                eprintln!("Failed to load movie cheats: {}", err);
+                std::process::exit(1);
+            });
+        }
+        cheats.locked = true;
+    }
+
+    if gbs.is_none() && movie.is_none() {
+        bus.cart.save_path = Some(config.save_file(&base_path, "sav"));
+        if let Err(err) = bus.cart.load_save() {
+            eprintln!("Failed to load save file: {}", err);
+        }
+    }
+
+    if let Some(gbs) = &gbs {
+        println!("{} - {} ({})", gbs.title, gbs.author, gbs.copyright);
+        println!("Playing song {}/{}", song + 1, gbs.song_count);
+        gbs.start_song(&mut cpu, &mut bus, song);
+    }else if skip_boot {
         bus.after_bootup();
         cpu.after_bootup();
     }
 
+    if !cheats.list.is_empty() {
+        cheats.print();
+    }
+    cheats.apply(&mut bus);
+
+    let mut recorder = opts.wav_path.as_ref().map(|path| {
+        Recorder::new(path, opts.wav_stems, config.audio_rate).expect("Failed to create wav file")
+    });
+    bus.apu.record_stems = opts.wav_stems;
+
+    // F5 starts numbered logs next to this path when --vgm isn't given
+    let vgm_base = opts.vgm_path.clone().map(PathBuf::from).unwrap_or_else(|| base_path.with_extension("vgm"));
+    let mut vgm_count = 0;
+    if opts.vgm_path.is_some() {
+        bus.apu.start_vgm(vgm_base.clone());
+        vgm_count += 1;
+    }
+
+    // Movies latch the input once per frame so playback doesn't depend on when events arrive,
+    // otherwise the live input goes to the joypad as soon as it changes
+    let mut live_input = 0;
+    bus.set_joypad(movie_input(&mut movie, live_input));
+
+    if let Some(frames) = opts.headless_frames {
+        run_headless(&mut cpu, &mut bus, &mut ppu, &mut dma, recorder.as_mut(), frames, |bus| {
+            cheats.write_ram(bus);
+            if movie.is_some() {
+                bus.set_joypad(movie_input(&mut movie, 0));
+            }
+        });
+        stop_vgm(&mut bus);
+        write_save(&bus);
+        return;
+    }
+
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem.window("quarrygb",4*160, 4*144)
+    let window = video_subsystem.window("quarrygb", config.scale*160, config.scale*144)
         .position_centered()
         .build()
         .unwrap();
     let mut canvas = window.into_canvas().build().unwrap();
     let creator = canvas.texture_creator();
     let mut texture = creator.create_texture_target(PixelFormatEnum::RGB24, 160, 144).unwrap();
+
+    // Sound debugging window, toggled with F2
+    let scope_window = video_subsystem.window("quarrygb sound", 3*visualizer::WIDTH as u32, 3*visualizer::HEIGHT as u32)
+        .hidden()
+        .build()
+        .unwrap();
+    let mut scope_canvas = scope_window.into_canvas().build().unwrap();
+    let scope_creator = scope_canvas.texture_creator();
+    let mut scope_texture = scope_creator.create_texture_target(PixelFormatEnum::RGB24, visualizer::WIDTH as u32, visualizer::HEIGHT as u32).unwrap();
+    let mut visualizer = Visualizer::new();
+    let mut show_visualizer = false;
+    let main_window_id = canvas.window().id();
+
+    // Controllers are opened as they are plugged in, SDL also reports the ones connected at startup.
+    // Rumble cartridges drive all of them.
+    let controller_subsystem = sdl_context.game_controller().unwrap();
+    let controllers: Rc<RefCell<Vec<GameController>>> = Rc::new(RefCell::new(Vec::new()));
+    let rumble_controllers = Rc::clone(&controllers);
+    bus.cart.on_rumble = Some(RumbleCallback(Box::new(move |on| {
+        let strength = if on {0xFFFF} else {0};
+        for controller in rumble_controllers.borrow_mut().iter_mut() {
+            controller.set_rumble(strength, strength, u32::MAX).ok();
+        }
+    })));
+    // Bindings from the config, then the ones in the --controls file on top
+    let mut controls = Controls::new();
+    if let Err(err) = controls.apply(&config.controls) {
+        eprintln!("Failed to load controls from {}: {}", config_path.unwrap_or_default().display(), err);
+        std::process::exit(1);
+    }
+    if let Some(path) = &opts.controls_path {
+        if let Err(err) = controls.load(Path::new(path)) {
+            eprintln!("Failed to load controls {}", err);
+            std::process::exit(1);
+        }
+    }
+    if let Mbc::Mbc7{..} = bus.cart.mbc {
+        controls.unbind_axes(&[Axis::LeftX, Axis::LeftY]);
+    }
+
     let mut event_pump = sdl_context.event_pump().unwrap();
     let audio_subsystem = sdl_context.audio().unwrap();
     let specs = AudioSpecDesired{
-        freq: Some(22_050),
+        freq: Some(config.audio_rate as i32),
         channels: Some(2),
         samples: Some(4096),
     };
     let queue = audio_subsystem.open_queue::<f32, _>(None, &specs).unwrap();
     queue.resume();
 
-    let mut t = Instant::now();
+    let mut pacer = Pacer::new(Duration::from_millis(60), 0.005, config.audio_rate as f64);
+    let mut resampler = Resampler::new();
+    let mut debugger = Debugger::new();
+    let mut frame_tstates = 0;
+    // Held rewind steps back through the snapshots, movies would desync so they can't be rewound
+    let mut rewind = movie.is_none().then(|| Rewind::new(REWIND_INTERVAL, REWIND_CAPACITY));
+    let mut is_rewinding = false;
+    let mut speed = SpeedControl::new(opts.fast_speed, opts.slow_speed);
+    let state_path = config.save_file(&base_path, "state");
+    let mut pause = PauseControl::new();
     loop {
         for event in event_pump.poll_iter() {
+            if let Some(gbs) = &gbs {
+                if let Some(next) = handle_gbs_event(gbs, song, &event) {
+                    song = next;
+                    stop_vgm(&mut bus);
+                    println!("Playing song {}/{}", song + 1, gbs.song_count);
+                    cpu = Cpu::new();
+                    bus = Bus::new(gbs.cartridge());
+                    ppu = Ppu::new();
+                    dma = Dma::new();
+                    bus.apu.record_stems = opts.wav_stems || show_visualizer;
+                    bus.apu.sample_rate = config.audio_rate as f64;
+                    ppu.palette = config.palette;
+                    cheats.apply(&mut bus);
+                    gbs.start_song(&mut cpu, &mut bus, song);
+                    if let Some(rewind) = &mut rewind {
+                        rewind.clear();
+                    }
+                    continue;
+                }
+            }
+            handle_vgm_event(&mut bus, &vgm_base, &mut vgm_count, &event);
+            handle_cheat_event(&mut bus, &mut cheats, &cheat_path, &event);
+            handle_movie_event(&mut movie, &event);
+            if handle_debugger_event(&mut cpu, &mut bus, &mut debugger, &mut cheats, &event) {
+                pacer = Pacer::new(Duration::from_millis(60), 0.005, config.audio_rate as f64);
+                queue.clear();
+                continue;
+            }
+            handle_tilt_event(&mut bus, canvas.window().size(), &event);
+            if handle_visualizer_event(&mut bus, &mut scope_canvas, main_window_id, &mut show_visualizer, opts.wav_stems, &event) {
+                continue;
+            }
+            handle_controller_event(&controller_subsystem, &controllers, &event);
+            for (action, pressed) in controls.actions(&event) {
+                match action {
+                    Action::Joypad(button) => live_input = controls::set_button(live_input, button, pressed),
+                    Action::Hotkey(Hotkey::Rewind) => is_rewinding = pressed,
+                    Action::Hotkey(Hotkey::SaveState) if pressed => {
+                        let snapshot = savestate::save_machine(&cpu, &bus, &ppu, &dma, config.state_cheats.then_some(&cheats));
+                        match savestate::write_file(&state_path, rom_crc, &snapshot) {
+                            Ok(()) => println!("Saved state to {}", state_path.display()),
+                            Err(err) => eprintln!("Failed to save state to {}: {}", state_path.display(), err),
+                        }
+                    }
+                    Action::Hotkey(Hotkey::LoadState) if pressed => {
+                        if movie.is_some() {
+                            println!("States can't be loaded while a movie is active");
+                            continue;
+                        }
+                        let result = savestate::read_file(&state_path, rom_crc).and_then(|snapshot| {
+                            savestate::load_machine(&snapshot, &mut cpu, &mut bus, &mut ppu, &mut dma, &mut cheats)
+                                .map_err(|err| format!("{}: {}", state_path.display(), err))
+                        });
+                        match result {
+                            Ok(()) => println!("Loaded state from {}", state_path.display()),
+                            Err(err) => eprintln!("Failed to load state {}", err),
+                        }
+                    }
+                    Action::Hotkey(Hotkey::Pause) if pressed => {
+                        println!("{}", if pause.toggle() {"Paused"} else {"Resumed"});
+                    }
+                    Action::Hotkey(Hotkey::FrameAdvance) if pressed => {
+                        pause.frame_advance();
+                    }
+                    Action::Hotkey(hotkey @ (Hotkey::Reset | Hotkey::HardReset)) if pressed => {
+                        if movie.is_some() {
+                            println!("Can't reset while a movie is active");
+                            continue;
+                        }
+                        let is_hard = hotkey == Hotkey::HardReset;
+                        bus = bus.reset(is_hard);
+                        cpu = Cpu::new();
+                        ppu = Ppu{palette: ppu.palette, ..Ppu::new()};
+                        dma = Dma::new();
+                        frame_tstates = 0;
+                        // Snapshots from before the reset would rewind into the previous session
+                        if let Some(rewind) = &mut rewind {
+                            rewind.clear();
+                        }
+                        if let Some(gbs) = &gbs {
+                            gbs.start_song(&mut cpu, &mut bus, song);
+                        }else if skip_boot {
+                            bus.after_bootup();
+                            cpu.after_bootup();
+                        }
+                        println!("{}", if is_hard {"Hard reset"} else {"Reset"});
+                    }
+                    Action::Hotkey(hotkey) => handle_hotkey(&mut bus, &mut speed, hotkey, pressed),
+                }
+            }
             handle_event(&mut bus, event);
         }
+        if movie.is_none() && live_input != bus.joypad() {
+            bus.set_joypad(live_input);
+        }
+        if !pause.should_run() {
+            queue.clear();
+            pacer.wait_video();
+            continue;
+        }
 
+        frame_tstates += step(&mut cpu, &mut bus, &mut ppu, &mut dma);
+        if frame_tstates >= TSTATES_PER_FRAME {
+            frame_tstates -= TSTATES_PER_FRAME;
+            // There is no vblank to wait for while the lcd is off
+            if (bus.lcdc & 0x80) == 0 {
+                pause.frame_done();
+            }
+            if movie.is_some() {
+                bus.set_joypad(movie_input(&mut movie, live_input));
+            }
+        }
 
-        let tstates = cpu.clock(&mut bus) * 4;
+        if ppu.entered_vblank {
+            ppu.entered_vblank = false;
+            pause.frame_done();
+            pacer.speed = speed.speed();
+            if let Some(rewind) = rewind.as_mut().filter(|_| is_rewinding) {
+                // Audio is muted while rewinding, the frame ran from the previous snapshot is dropped
+                if let Some(snapshot) = rewind.pop() {
+                    if let Err(err) = savestate::load_machine(&snapshot, &mut cpu, &mut bus, &mut ppu, &mut dma, &mut cheats) {
+                        eprintln!("Failed to rewind: {}", err);
+                    }
+                }
+                bus.apu.buffer.clear();
+                bus.apu.clear_stems();
+                if pacer.should_present() {
+                    present(&mut canvas, &mut texture, &ppu.framebuffer);
+                }
+                queue.clear();
+                pacer.wait_video();
+                continue;
+            }
+            if let Some(rewind) = &mut rewind {
+                rewind.frame(|| savestate::save_machine(&cpu, &bus, &ppu, &dma, config.state_cheats.then_some(&cheats)));
+            }
+            cheats.write_ram(&mut bus);
+            let audio_buffer = std::mem::take(&mut bus.apu.buffer);
+            if let Some(recorder) = &mut recorder {
+                recorder.record(&bus.apu, &audio_buffer).expect("Failed to write wav file");
+            }
+            if show_visualizer {
+                visualizer.draw(&bus.apu);
+                scope_texture.update(None, &visualizer.framebuffer, 3*visualizer::WIDTH).unwrap();
+                scope_canvas.copy(&scope_texture, None, None).unwrap();
+                scope_canvas.present();
+            }
+            bus.apu.clear_stems();
+            if pacer.should_present() {
+                present(&mut canvas, &mut texture, &ppu.framebuffer);
+            }
+            // The speed only changes how the frame's audio is queued, the recording above
+            // always runs at the nominal rate
+            match pacer.output_ratio(queue.size()).filter(|_| !bus.apu.is_muted()) {
+                Some(ratio) => {
+                    queue.queue_audio(&resampler.resample(&audio_buffer, ratio)).unwrap();
+                    pacer.wait_audio(|| queue.size());
+                }
+                None => {
+                    queue.clear();
+                    pacer.wait_video();
+                }
+            }
+        }
+    }
+}
+
+// Runs one cpu instruction and clocks the rest of the system for its duration, returns the tstates taken
+pub fn step(cpu: &mut Cpu, bus: &mut Bus, ppu: &mut Ppu, dma: &mut Dma) -> usize {
+    bus.cpu_access_tstates = Some(0);
+    bus.apu_ticked_ahead = 0;
+    let tstates = cpu.clock(bus) * 4;
+    bus.cpu_access_tstates = None;
 
-        for tstate in 0..tstates {
+    for tstate in 0..tstates {
 
-            ppu.tick(&mut bus);
+        ppu.tick(bus);
 
-            dma.tick(&mut bus, tstate);
+        dma.tick(bus, tstate);
 
-            bus.timer.tick(&mut bus.iff);
+        bus.timer.tick(&mut bus.iff);
 
+        // Tstates the apu was ticked ahead for during the instruction are skipped
+        if tstate >= bus.apu_ticked_ahead {
             bus.apu.tick(bus.timer.read_div());
-            
-            if ppu.entered_vblank {
-                let audio_buffer = std::mem::take(&mut bus.apu.buffer);
-                queue.queue_audio(&audio_buffer).unwrap();
-                ppu.entered_vblank = false;
-                texture.update(None, &ppu.framebuffer, 3*160).unwrap();
-                canvas.copy(&texture, None, None).unwrap();
-                canvas.present();
-                let elapsed = t.elapsed().as_micros() as u64;
-                thread::sleep(Duration::from_micros(16750 - (elapsed).clamp(0, 16749)));
-                t = Instant::now();
-                // let elapsed = now.elapsed();
-                // let sleeptime = Duration::from_millis(59700) - elapsed;
-                // thread::sleep(sleeptime);
-                // let now = Instant::now();
-            }
         }
     }
+    bus.cart.tick(tstates);
+    tstates
 }
 
-pub fn handle_event(bus: &mut Bus, event: Event) {
+// Runs a fixed number of frames as fast as possible without opening a window or audio device.
+// Frames are counted in tstates so this also works while the lcd is off, on_frame runs after each one.
+pub fn run_headless(cpu: &mut Cpu, bus: &mut Bus, ppu: &mut Ppu, dma: &mut Dma, mut recorder: Option<&mut Recorder>, frames: u64, mut on_frame: impl FnMut(&mut Bus)) {
+    let mut tstates = 0;
+    for _ in 0..frames {
+        while tstates < TSTATES_PER_FRAME {
+            tstates += step(cpu, bus, ppu, dma);
+        }
+        tstates -= TSTATES_PER_FRAME;
+        ppu.entered_vblank = false;
+        on_frame(bus);
+
+        let audio_buffer = std::mem::take(&mut bus.apu.buffer);
+        if let Some(recorder) = &mut recorder {
+            recorder.record(&bus.apu, &audio_buffer).expect("Failed to write wav file");
+        }
+        bus.apu.clear_stems();
+    }
+}
+
+pub fn present(canvas: &mut Canvas<Window>, texture: &mut Texture, framebuffer: &[u8]) {
+    texture.update(None, framebuffer, 3*160).unwrap();
+    canvas.copy(texture, None, None).unwrap();
+    canvas.present();
+}
+
+pub fn quit(bus: &mut Bus) -> ! {
+    stop_vgm(bus);
+    write_save(bus);
+    std::process::exit(0)
+}
+
+// Input for the next frame, live when there is no movie or it stopped working
+pub fn movie_input(movie: &mut Option<Movie>, live: u8) -> u8 {
+    let Some(active) = movie else {
+        return live;
+    };
+    match active.next_input(live) {
+        Ok(input) => input,
+        Err(err) => {
+            eprintln!("Failed to write movie {}, recording stopped: {}", active.path.display(), err);
+            *movie = None;
+            live
+        }
+    }
+}
+
+pub fn write_save(bus: &Bus) {
+    if let Err(err) = bus.cart.write_save() {
+        eprintln!("Failed to write save file: {}", err);
+    }
+}
+
+pub fn stop_vgm(bus: &mut Bus) {
+    match bus.apu.stop_vgm() {
+        Some(Ok(path)) => println!("VGM log saved to {}", path.display()),
+        Some(Err(err)) => eprintln!("Failed to save VGM log: {}", err),
+        None => (),
+    }
+}
+
+// F2 shows / hides the sound debugging window, returns true if the event was consumed
+pub fn handle_visualizer_event(bus: &mut Bus, canvas: &mut Canvas<Window>, main_window_id: u32, show: &mut bool, wav_stems: bool, event: &Event) -> bool {
     match event {
-        Event::Quit{..}
-        | Event::KeyDown {
-            keycode: Some(Keycode::Escape),
-            ..
-        } => std::process::exit(0),
-        
         Event::KeyDown {
-            keycode: Some(key),
+            keycode: Some(Keycode::F2),
+            repeat: false,
+            ..
+        } => *show ^= true,
+        Event::Window {
+            window_id,
+            win_event: WindowEvent::Close,
             ..
-        } => match key {
-            Keycode::Down => bus.jpad_down = true,
-            Keycode::Up => bus.jpad_up = true,
-            Keycode::Left => bus.jpad_left = true,
-            Keycode::Right => bus.jpad_right = true,
-            Keycode::Return => bus.jpad_start = true,
-            Keycode::Backspace => bus.jpad_select = true,
-            Keycode::X => bus.jpad_b = true,
-            Keycode::Z => bus.jpad_a = true,
-
-            Keycode::U => bus.apu.dbgch1 ^= true,
-            Keycode::I => bus.apu.dbgch2 ^= true,
-            Keycode::O => bus.apu.dbgch3 ^= true,
-            Keycode::P => bus.apu.dbgch4 ^= true,
-
-            Keycode::Q => bus.debug_inst ^= true,
+        } if *window_id == canvas.window().id() => *show = false,
+        // The hidden scope window keeps sdl from sending Quit when the main window closes
+        Event::Window {
+            window_id,
+            win_event: WindowEvent::Close,
+            ..
+        } if *window_id == main_window_id => quit(bus),
+        _ => return false,
+    }
+    if *show {
+        canvas.window_mut().show();
+    }else {
+        canvas.window_mut().hide();
+    }
+    bus.apu.record_stems = wav_stems || *show;
+    true
+}
+
+// MBC7 tilt from the mouse while the left button is held, the numpad arrows or the left analog stick
+pub fn handle_tilt_event(bus: &mut Bus, window_size: (u32, u32), event: &Event) {
+    let tilt = &mut bus.cart.tilt;
+    match event {
+        Event::MouseMotion {x, y, mousestate, ..} if mousestate.left() => {
+            let (w, h) = (window_size.0 as f32 / 2.0, window_size.1 as f32 / 2.0);
+            *tilt = ((*x as f32 - w) / w, (*y as f32 - h) / h);
+        }
+        Event::MouseButtonUp {mouse_btn: MouseButton::Left, ..} => *tilt = (0.0, 0.0),
+        Event::ControllerAxisMotion {axis: Axis::LeftX, value, ..} => tilt.0 = *value as f32 / 32767.0,
+        Event::ControllerAxisMotion {axis: Axis::LeftY, value, ..} => tilt.1 = *value as f32 / 32767.0,
+        Event::KeyDown {keycode: Some(key), ..} => match key {
+            Keycode::Kp4 => tilt.0 = -1.0,
+            Keycode::Kp6 => tilt.0 = 1.0,
+            Keycode::Kp8 => tilt.1 = -1.0,
+            Keycode::Kp2 => tilt.1 = 1.0,
+            _ => (),
+        },
+        Event::KeyUp {keycode: Some(key), ..} => match key {
+            Keycode::Kp4 | Keycode::Kp6 => tilt.0 = 0.0,
+            Keycode::Kp8 | Keycode::Kp2 => tilt.1 = 0.0,
             _ => (),
         },
+        _ => (),
+    }
+}
 
+// F5 starts / stops a vgm log, F6 marks the loop point of the running log
+pub fn handle_vgm_event(bus: &mut Bus, base: &Path, count: &mut u32, event: &Event) {
+    match event {
+        Event::KeyDown {
+            keycode: Some(Keycode::F5),
+            repeat: false,
+            ..
+        } => {
+            if bus.apu.vgm.is_some() {
+                stop_vgm(bus);
+            }else {
+                let path = if *count == 0 {
+                    base.to_path_buf()
+                }else {
+                    let stem = base.file_stem().unwrap_or_default().to_string_lossy();
+                    base.with_file_name(format!("{}_{}.vgm", stem, *count + 1))
+                };
+                println!("VGM logging to {}", path.display());
+                bus.apu.start_vgm(path);
+                *count += 1;
+            }
+        }
+        Event::KeyDown {
+            keycode: Some(Keycode::F6),
+            repeat: false,
+            ..
+        } if bus.apu.vgm.is_some() => {
+            bus.apu.mark_vgm_loop();
+            println!("VGM loop point set");
+        }
+        _ => (),
+    }
+}
 
-        Event::KeyUp {
-            keycode: Some(key),
+// F9 pauses and reads debugger commands from the terminal, returns true if it ran
+pub fn handle_debugger_event(cpu: &mut Cpu, bus: &mut Bus, debugger: &mut Debugger, cheats: &mut Cheats, event: &Event) -> bool {
+    match event {
+        Event::KeyDown {
+            keycode: Some(Keycode::F9),
+            repeat: false,
             ..
-        } => match key {
-            Keycode::Down => bus.jpad_down = false,
-            Keycode::Up => bus.jpad_up = false,
-            Keycode::Left => bus.jpad_left = false,
-            Keycode::Right => bus.jpad_right = false,
-            Keycode::Return => bus.jpad_start = false,
-            Keycode::Backspace => bus.jpad_select = false,
-            Keycode::X => bus.jpad_b = false,
-            Keycode::Z => bus.jpad_a = false,
-            _ => (),
+        } => {
+            debugger.prompt(cpu, bus, cheats);
+            true
+        }
+        _ => false,
+    }
+}
+
+// F10 toggles read-only playback, F11 takes over a read-write playback and records from the current frame
+pub fn handle_movie_event(movie: &mut Option<Movie>, event: &Event) {
+    let Some(movie) = movie else {
+        return;
+    };
+    match event {
+        Event::KeyDown {
+            keycode: Some(Keycode::F10),
+            repeat: false,
+            ..
+        } => {
+            movie.read_only ^= true;
+            println!("Movie {}", if movie.read_only {"read-only"} else {"read-write"});
+        }
+        Event::KeyDown {
+            keycode: Some(Keycode::F11),
+            repeat: false,
+            ..
+        } if movie.mode != Mode::Recording => {
+            if movie.read_only {
+                println!("Movie is read-only, press F10 first to record over it");
+            }else if let Err(err) = movie.resume_recording() {
+                eprintln!("Failed to resume recording {}: {}", movie.path.display(), err);
+            }else {
+                println!("Recording movie from frame {}", movie.frame);
+            }
+        }
+        _ => (),
+    }
+}
+
+// F7 turns all cheats on / off, F8 reloads the cheat file
+pub fn handle_cheat_event(bus: &mut Bus, cheats: &mut Cheats, path: &Path, event: &Event) {
+    if let Event::KeyDown {keycode: Some(Keycode::F7 | Keycode::F8), repeat: false, ..} = event {
+        if cheats.locked {
+            println!("Cheats can't change while a movie is active");
+            return;
+        }
+    }
+    match event {
+        Event::KeyDown {
+            keycode: Some(Keycode::F7),
+            repeat: false,
+            ..
+        } => cheats.enabled ^= true,
+        Event::KeyDown {
+            keycode: Some(Keycode::F8),
+            repeat: false,
+            ..
+        } => match Cheats::load(path) {
+            Ok(loaded) => cheats.list = loaded.list,
+            Err(err) => {
+                eprintln!("Failed to load cheats from {}: {}", path.display(), err);
+                return;
+            }
+        },
+        _ => return,
+    }
+    cheats.print();
+    cheats.apply(bus);
+}
+
+// Hotkeys that don't need more than the bus, toggles only act when pressed
+pub fn handle_hotkey(bus: &mut Bus, speed: &mut SpeedControl, hotkey: Hotkey, pressed: bool) {
+    match hotkey {
+        Hotkey::FastForward => speed.is_fast_forward = pressed,
+        _ if !pressed => (),
+        Hotkey::Uncapped => {
+            speed.is_uncapped ^= true;
+            println!("Speed {}", if speed.is_uncapped {"uncapped"} else {"capped"});
+        }
+        Hotkey::SlowMotion => {
+            speed.is_slow ^= true;
+            println!("Slow motion {}", if speed.is_slow {"on"} else {"off"});
+        }
+        Hotkey::MuteCh1 => bus.apu.dbgch1 ^= true,
+        Hotkey::MuteCh2 => bus.apu.dbgch2 ^= true,
+        Hotkey::MuteCh3 => bus.apu.dbgch3 ^= true,
+        Hotkey::MuteCh4 => bus.apu.dbgch4 ^= true,
+        Hotkey::Mute => bus.apu.muted ^= true,
+        Hotkey::Quit => quit(bus),
+        Hotkey::Pause | Hotkey::FrameAdvance | Hotkey::Reset | Hotkey::HardReset
+        | Hotkey::Rewind | Hotkey::SaveState | Hotkey::LoadState => (),
+    }
+}
+
+pub fn handle_controller_event(subsystem: &GameControllerSubsystem, controllers: &RefCell<Vec<GameController>>, event: &Event) {
+    match event {
+        Event::ControllerDeviceAdded {which, ..} => match subsystem.open(*which) {
+            Ok(controller) => {
+                let mut controllers = controllers.borrow_mut();
+                if controllers.iter().all(|open| open.instance_id() != controller.instance_id()) {
+                    println!("Controller connected: {}", controller.name());
+                    controllers.push(controller);
+                }
+            }
+            Err(err) => eprintln!("Failed to open controller {}: {}", which, err),
         },
+        Event::ControllerDeviceRemoved {which, ..} => controllers.borrow_mut().retain(|controller| {
+            if controller.instance_id() == *which {
+                println!("Controller disconnected: {}", controller.name());
+            }
+            controller.instance_id() != *which
+        }),
+        _ => (),
+    }
+}
+
+// Left / Right switch to the previous / next song, returns the song to restart with
+pub fn handle_gbs_event(gbs: &Gbs, song: u8, event: &Event) -> Option<u8> {
+    match event {
+        Event::KeyDown {
+            keycode: Some(Keycode::Left),
+            repeat: false,
+            ..
+        } => Some(if song == 0 {gbs.song_count - 1} else {song - 1}),
+        Event::KeyDown {
+            keycode: Some(Keycode::Right),
+            repeat: false,
+            ..
+        } => Some((song + 1) % gbs.song_count),
+        _ => None,
+    }
+}
+
+// Keys that can't be remapped, the joypad and hotkeys go through Controls
+pub fn handle_event(bus: &mut Bus, event: Event) {
+    match event {
+        Event::Quit{..} => quit(bus),
+        Event::KeyDown {
+            keycode: Some(Keycode::Q),
+            ..
+        } => bus.debug_inst ^= true,
         _ => (),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs a rom built on blargg's test shell until it reports at $A000, returns the result code and its text
+    fn run_blargg(path: &Path, max_frames: u64) -> (u8, String) {
+        let rom = fs::read(path).unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+        let mut bus = Bus::new(Cartridge::new(rom, Vec::new()));
+        let mut cpu = Cpu::new();
+        let (mut ppu, mut dma) = (Ppu::new(), Dma::new());
+        bus.after_bootup();
+        cpu.after_bootup();
+        for _ in 0..max_frames {
+            run_headless(&mut cpu, &mut bus, &mut ppu, &mut dma, None, 1, |_| ());
+            let signature = [bus.readu8(0xA001), bus.readu8(0xA002), bus.readu8(0xA003)];
+            let status = bus.readu8(0xA000);
+            if signature == [0xDE, 0xB0, 0x61] && status != 0x80 {
+                let text = (0xA004..0xC000).map(|addr| bus.readu8(addr)).take_while(|&c| c != 0).map(char::from).collect();
+                return (status, text);
+            }
+        }
+        panic!("{}: no result after {} frames", path.display(), max_frames);
+    }
+
+    // Steps the way the main loop does, returns the number of frames that ran
+    fn run_paused(pause: &mut PauseControl, cpu: &mut Cpu, bus: &mut Bus, ppu: &mut Ppu, dma: &mut Dma, polls: u32) -> u32 {
+        let mut frames = 0;
+        for _ in 0..polls {
+            if !pause.should_run() {
+                continue;
+            }
+            step(cpu, bus, ppu, dma);
+            if ppu.entered_vblank {
+                ppu.entered_vblank = false;
+                pause.frame_done();
+                frames += 1;
+            }
+        }
+        frames
+    }
+
+    #[test]
+    fn frame_advance_steps_one_frame() {
+        // A rom spinning on a 12 tstate jr, POLLS runs just over 5 frames
+        const POLLS: u32 = 30_000;
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x18, 0xFE]);
+        let mut bus = Bus::new(Cartridge::new(rom, Vec::new()));
+        let mut cpu = Cpu::new();
+        let (mut ppu, mut dma) = (Ppu::new(), Dma::new());
+        bus.after_bootup();
+        cpu.after_bootup();
+        let mut pause = PauseControl::new();
+        assert_eq!(run_paused(&mut pause, &mut cpu, &mut bus, &mut ppu, &mut dma, POLLS), 5);
+
+        // The first press finishes the frame being run and pauses
+        pause.frame_advance();
+        assert_eq!(run_paused(&mut pause, &mut cpu, &mut bus, &mut ppu, &mut dma, POLLS), 1);
+        assert_eq!(run_paused(&mut pause, &mut cpu, &mut bus, &mut ppu, &mut dma, POLLS), 0);
+        for presses in 1..=3 {
+            for _ in 0..presses {
+                pause.frame_advance();
+            }
+            assert_eq!(run_paused(&mut pause, &mut cpu, &mut bus, &mut ppu, &mut dma, POLLS), presses);
+            assert_eq!(run_paused(&mut pause, &mut cpu, &mut bus, &mut ppu, &mut dma, POLLS), 0);
+        }
+
+        assert!(!pause.toggle());
+        assert_eq!(run_paused(&mut pause, &mut cpu, &mut bus, &mut ppu, &mut dma, POLLS), 5);
+    }
+
+    // The roms aren't part of the source, put blargg's dmg_sound singles in test_roms/dmg_sound
+    // and run with --ignored
+    #[test]
+    #[ignore]
+    fn dmg_sound_wave_timing() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("test_roms/dmg_sound");
+        for name in ["09-wave read while on.gb", "10-wave trigger while on.gb", "12-wave write while on.gb"] {
+            let (code, text) = run_blargg(&dir.join(name), 60*60);
+            assert_eq!(code, 0, "{}: {}", name, text);
+        }
+    }
+}
 /* 
 
 7A      :LD A, D
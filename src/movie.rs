@@ -0,0 +1,181 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Input movie, one joypad byte per frame from power-on after a small header:
+// "QGBM", format, flags, rom crc32, emulator version length and text,
+// then the number of active cheats and the length and text of each. Format 1 has no cheats.
+const MAGIC: &[u8; 4] = b"QGBM";
+const FORMAT: u8 = 2;
+const FLAG_SKIP_BOOT: u8 = 0x01;
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Recording,
+    Playing,
+    Finished, // playback ran out, the live input is passed through
+}
+
+#[derive(Debug)]
+pub struct Movie {
+    pub path: PathBuf,
+    pub mode: Mode,
+    pub read_only: bool, // playback can't be taken over by resume_recording
+    pub skip_boot: bool,
+    pub rom_crc: u32,
+    pub version: String,
+    pub cheats: Vec<String>, // codes that were on while recording, playback runs with the same ones
+    pub frames: Vec<u8>,
+    pub frame: usize, // next frame to record or play
+    header_len: usize,
+    file: Option<BufWriter<File>>,
+}
+
+impl Movie {
+    pub fn record(path: &Path, rom_crc: u32, skip_boot: bool, cheats: Vec<String>) -> io::Result<Self> {
+        let mut movie = Movie{
+            path: path.to_path_buf(),
+            mode: Mode::Recording,
+            read_only: false,
+            skip_boot,
+            rom_crc,
+            version: VERSION.to_string(),
+            cheats,
+            frames: Vec::new(),
+            frame: 0,
+            header_len: 0,
+            file: None,
+        };
+        let header = movie.header();
+        movie.header_len = header.len();
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&header)?;
+        file.flush()?;
+        movie.file = Some(file);
+        Ok(movie)
+    }
+
+    pub fn play(path: &Path) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let invalid = || format!("{}: not a movie file", path.display());
+        if data.len() < 11 || &data[0..4] != MAGIC {
+            return Err(invalid());
+        }
+        if data[4] != 1 && data[4] != FORMAT {
+            return Err(format!("{}: unsupported movie format {}", path.display(), data[4]));
+        }
+        let version_len = data[10] as usize;
+        let mut header_len = 11 + version_len;
+        let version = data.get(11..header_len).ok_or_else(invalid)?;
+        let mut cheats = Vec::new();
+        if data[4] >= 2 {
+            let count = *data.get(header_len).ok_or_else(invalid)?;
+            header_len += 1;
+            for _ in 0..count {
+                let len = *data.get(header_len).ok_or_else(invalid)? as usize;
+                let text = data.get(header_len + 1..header_len + 1 + len).ok_or_else(invalid)?;
+                cheats.push(String::from_utf8_lossy(text).into_owned());
+                header_len += 1 + len;
+            }
+        }
+        Ok(Movie{
+            path: path.to_path_buf(),
+            mode: Mode::Playing,
+            read_only: true,
+            skip_boot: (data[5] & FLAG_SKIP_BOOT) != 0,
+            rom_crc: u32::from_le_bytes(data[6..10].try_into().unwrap()),
+            version: String::from_utf8_lossy(version).into_owned(),
+            cheats,
+            frames: data[header_len..].to_vec(),
+            frame: 0,
+            header_len,
+            file: None,
+        })
+    }
+
+    fn header(&self) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.push(FORMAT);
+        header.push(if self.skip_boot {FLAG_SKIP_BOOT} else {0});
+        header.extend_from_slice(&self.rom_crc.to_le_bytes());
+        header.push(self.version.len() as u8);
+        header.extend_from_slice(self.version.as_bytes());
+        header.push(self.cheats.len() as u8);
+        for cheat in &self.cheats {
+            header.push(cheat.len() as u8);
+            header.extend_from_slice(cheat.as_bytes());
+        }
+        header
+    }
+
+    // Mismatches are reported rather than refused, the movie may still play back fine
+    pub fn check(&self, rom_crc: u32) {
+        if self.rom_crc != rom_crc {
+            eprintln!("Movie was recorded with a different rom (crc32 {:08X}, this one is {:08X})", self.rom_crc, rom_crc);
+        }
+        if self.version != VERSION {
+            eprintln!("Movie was recorded with version {}, this is {}, playback may desync", self.version, VERSION);
+        }
+    }
+
+    // Called at power-on and at the start of every frame, returns the input to use for that frame
+    pub fn next_input(&mut self, live: u8) -> io::Result<u8> {
+        match self.mode {
+            Mode::Recording => {
+                self.frames.push(live);
+                self.frame += 1;
+                if let Some(file) = &mut self.file {
+                    file.write_all(&[live])?;
+                    file.flush()?;
+                }
+                Ok(live)
+            }
+            Mode::Playing => match self.frames.get(self.frame) {
+                Some(&input) => {
+                    self.frame += 1;
+                    Ok(input)
+                }
+                None => {
+                    println!("Movie finished after {} frames", self.frame);
+                    self.mode = Mode::Finished;
+                    Ok(live)
+                }
+            },
+            Mode::Finished => Ok(live),
+        }
+    }
+
+    // Drops the rest of the movie and keeps recording from the current frame
+    pub fn resume_recording(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).open(&self.path)?;
+        file.set_len((self.header_len + self.frame) as u64)?;
+        let mut file = BufWriter::new(file);
+        file.seek(SeekFrom::End(0))?;
+        self.frames.truncate(self.frame);
+        self.file = Some(file);
+        self.mode = Mode::Recording;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_keeps_cheats() {
+        let path = std::env::temp_dir().join(format!("quarrygb_movie_{}.qgbm", std::process::id()));
+        let cheats = vec!["01FF34C0".to_string(), "00A-17B-C49".to_string()];
+        let mut movie = Movie::record(&path, 0x12345678, true, cheats.clone()).unwrap();
+        movie.next_input(0x01).unwrap();
+        movie.next_input(0x82).unwrap();
+        drop(movie);
+
+        let movie = Movie::play(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(movie.cheats, cheats);
+        assert_eq!(movie.frames, [0x01, 0x82]);
+        assert_eq!((movie.rom_crc, movie.skip_boot), (0x12345678, true));
+    }
+}
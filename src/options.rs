@@ -0,0 +1,82 @@
+#[derive(Debug, Default)]
+pub struct Options {
+    pub rom_path: String,
+    pub debugmode: bool,
+    pub wav_path: Option<String>,
+    pub wav_stems: bool,
+    pub headless_frames: Option<u64>,
+    pub track: Option<u8>, // 1 based gbs song number
+    pub vgm_path: Option<String>,
+    pub camera_image: Option<String>, // PGM / PPM still for the Game Boy Camera
+    pub entry: Option<String>, // rom to load from a zip archive
+    pub patches: Vec<String>, // applied in order, patches named after the rom are used when empty
+    pub cheats: Vec<String>, // Game Genie / GameShark codes added to the ones in the cheat file
+    pub record_path: Option<String>, // input movie to record from power-on
+    pub play_path: Option<String>, // input movie to play back
+    pub fast_speed: f64, // speed multiplier while fast-forwarding
+    pub slow_speed: f64, // speed multiplier in slow motion
+    pub controls_path: Option<String>, // ini file with key and controller bindings
+    pub config_path: Option<String>, // replaces the config.ini in the user config dir
+}
+
+impl Options {
+    // usage: quarrygbemu <rom> [debugmode] [--wav <file>] [--stems] [--headless <frames>] [--track <n>] [--vgm <file>] [--camera <image>] [--entry <name>] [--patch <file>...] [--cheat <code>...] [--record <movie>] [--play <movie>] [--fast <x>] [--slow <x>] [--controls <file>] [--config <file>]
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut opts = Options{
+            fast_speed: 4.0,
+            slow_speed: 0.5,
+            ..Options::default()
+        };
+        let mut positional = Vec::new();
+        let mut args = args.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--wav" => opts.wav_path = Some(next_value(&mut args, arg)?),
+                "--vgm" => opts.vgm_path = Some(next_value(&mut args, arg)?),
+                "--camera" => opts.camera_image = Some(next_value(&mut args, arg)?),
+                "--entry" => opts.entry = Some(next_value(&mut args, arg)?),
+                "--patch" => opts.patches.push(next_value(&mut args, arg)?),
+                "--cheat" => opts.cheats.push(next_value(&mut args, arg)?),
+                "--record" => opts.record_path = Some(next_value(&mut args, arg)?),
+                "--play" => opts.play_path = Some(next_value(&mut args, arg)?),
+                "--config" => opts.config_path = Some(next_value(&mut args, arg)?),
+                "--controls" => opts.controls_path = Some(next_value(&mut args, arg)?),
+                "--stems" => opts.wav_stems = true,
+                "--headless" => {
+                    let frames = next_value(&mut args, arg)?;
+                    opts.headless_frames = Some(frames.parse().map_err(|_| format!("Invalid frame count: {}", frames))?);
+                }
+                "--fast" => {
+                    let speed = next_value(&mut args, arg)?;
+                    opts.fast_speed = speed.parse().ok().filter(|&speed: &f64| speed > 1.0)
+                        .ok_or(format!("Invalid fast-forward speed: {}, must be above 1", speed))?;
+                }
+                "--slow" => {
+                    let speed = next_value(&mut args, arg)?;
+                    opts.slow_speed = speed.parse().ok().filter(|&speed: &f64| speed > 0.0 && speed < 1.0)
+                        .ok_or(format!("Invalid slow motion speed: {}, must be between 0 and 1", speed))?;
+                }
+                "--track" => {
+                    let track = next_value(&mut args, arg)?;
+                    opts.track = Some(track.parse().map_err(|_| format!("Invalid track number: {}", track))?);
+                }
+                flag if flag.starts_with("--") => return Err(format!("Unknown option: {}", flag)),
+                _ => positional.push(arg.clone()),
+            }
+        }
+        let mut positional = positional.into_iter();
+        opts.rom_path = positional.next().ok_or("No rom file given")?;
+        opts.debugmode = positional.next().map(|s| s.parse::<bool>().unwrap_or(false)).unwrap_or(false);
+        if opts.wav_stems && opts.wav_path.is_none() {
+            return Err("--stems requires --wav <file>".to_string());
+        }
+        if opts.record_path.is_some() && opts.play_path.is_some() {
+            return Err("--record and --play can't be used together".to_string());
+        }
+        Ok(opts)
+    }
+}
+
+fn next_value<'a>(args: &mut impl Iterator<Item = &'a String>, flag: &str) -> Result<String, String> {
+    args.next().cloned().ok_or(format!("Missing value for {}", flag))
+}
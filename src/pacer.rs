@@ -0,0 +1,246 @@
+use std::time::{Duration, Instant};
+use std::thread;
+
+pub const FRAME_TIME: Duration = Duration::from_micros(16750);
+
+// Bytes per queued stereo f32 sample
+pub const BYTES_PER_SAMPLE: u32 = 2 * 4;
+
+#[derive(Debug)]
+pub struct Pacer {
+    pub target_latency: Duration,
+    pub max_delta: f64, // max resampling adjustment, 0.005 => +-0.5%
+    pub sample_rate: f64,
+    pub speed: f64, // emulation speed multiplier, infinite when uncapped
+    pub last_frame: Instant,
+    pub last_present: Instant,
+}
+
+// Linear interpolation from the apu output rate to the rate fed to the audio queue.
+// The last stereo frame of each buffer is kept so consecutive buffers join up.
+#[derive(Debug, Default)]
+pub struct Resampler {
+    pub pos: f64, // position of the next output frame in input frames, 0.0 is the kept frame
+    pub last: [f32; 2],
+}
+
+// Hold to fast-forward, toggles for uncapped speed and slow motion
+#[derive(Debug)]
+pub struct SpeedControl {
+    pub fast: f64,
+    pub slow: f64,
+    pub is_fast_forward: bool,
+    pub is_uncapped: bool,
+    pub is_slow: bool,
+}
+
+// Pausing and frame advance take effect at the end of the frame being run
+#[derive(Debug, Default)]
+pub struct PauseControl {
+    pub is_paused: bool,
+    is_frame_done: bool,
+    advances: u32,
+}
+
+impl Pacer {
+    pub fn new(target_latency: Duration, max_delta: f64, sample_rate: f64) -> Self {
+        Pacer{
+            target_latency,
+            max_delta,
+            sample_rate,
+            speed: 1.0,
+            last_frame: Instant::now(),
+            last_present: Instant::now(),
+        }
+    }
+
+    pub fn frame_time(&self) -> Duration {
+        if self.speed.is_infinite() {
+            Duration::ZERO
+        }else {
+            FRAME_TIME.div_f64(self.speed)
+        }
+    }
+
+    pub fn target_samples(&self) -> u32 {
+        (self.target_latency.as_secs_f64() * self.sample_rate) as u32
+    }
+
+    // Dynamic rate control: nudge the resampling ratio so the queue drifts
+    // back towards the target fill level instead of growing or draining.
+    pub fn rate_ratio(&self, queued_bytes: u32) -> f64 {
+        let queued = (queued_bytes / BYTES_PER_SAMPLE) as f64;
+        let target = self.target_samples() as f64;
+        let error = ((target - queued) / target).clamp(-1.0, 1.0);
+        1.0 + self.max_delta * error
+    }
+
+    // Resampling ratio from the apu output to the queue, None when the frame's audio is skipped.
+    // Above normal speed (or uncapped) the queue would pile up so audio is skipped,
+    // slow motion is stretched to more samples. The emulated apu itself never sees the speed.
+    pub fn output_ratio(&self, queued_bytes: u32) -> Option<f64> {
+        (self.speed <= 1.0).then(|| self.rate_ratio(queued_bytes) / self.speed)
+    }
+
+    // Audio driven pacing, block until the device has drained the queue down to the target latency.
+    // Never waits longer than a few frames so a stalled device can't freeze the emulator.
+    pub fn wait_audio(&mut self, queued_bytes: impl Fn() -> u32) {
+        let target = self.target_samples() * BYTES_PER_SAMPLE;
+        while queued_bytes() > target && self.last_frame.elapsed() < 4 * self.frame_time() {
+            thread::sleep(Duration::from_micros(500));
+        }
+        self.last_frame = Instant::now();
+    }
+
+    // Video timed pacing, used when there is no audio to sync to.
+    pub fn wait_video(&mut self) {
+        let elapsed = self.last_frame.elapsed();
+        if elapsed < self.frame_time() {
+            thread::sleep(self.frame_time() - elapsed);
+        }
+        self.last_frame = Instant::now();
+    }
+
+    // Frame skipping above normal speed, the screen is still updated at most once per real frame
+    pub fn should_present(&mut self) -> bool {
+        if self.speed > 1.0 && self.last_present.elapsed() < FRAME_TIME {
+            return false;
+        }
+        self.last_present = Instant::now();
+        true
+    }
+}
+
+impl Resampler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // ratio is output frames per input frame, >1.0 produces more samples
+    pub fn resample(&mut self, input: &[f32], ratio: f64) -> Vec<f32> {
+        let frames = input.len() / 2;
+        let last = self.last;
+        let frame = |i: usize| if i == 0 {last} else {[input[2*i - 2], input[2*i - 1]]};
+        let mut output = Vec::with_capacity(2 * (frames as f64 * ratio) as usize + 2);
+        while self.pos < frames as f64 {
+            let i = self.pos as usize;
+            let t = (self.pos - i as f64) as f32;
+            let (a, b) = (frame(i), frame(i + 1));
+            output.push(a[0] + (b[0] - a[0])*t);
+            output.push(a[1] + (b[1] - a[1])*t);
+            self.pos += 1.0 / ratio;
+        }
+        self.pos -= frames as f64;
+        if frames > 0 {
+            self.last = frame(frames);
+        }
+        output
+    }
+}
+
+impl SpeedControl {
+    pub fn new(fast: f64, slow: f64) -> Self {
+        SpeedControl{
+            fast,
+            slow,
+            is_fast_forward: false,
+            is_uncapped: false,
+            is_slow: false,
+        }
+    }
+
+    pub fn speed(&self) -> f64 {
+        if self.is_uncapped {
+            f64::INFINITY
+        }else if self.is_fast_forward {
+            self.fast
+        }else if self.is_slow {
+            self.slow
+        }else {
+            1.0
+        }
+    }
+}
+
+impl PauseControl {
+    pub fn new() -> Self {
+        PauseControl{
+            is_paused: false,
+            is_frame_done: false,
+            advances: 0,
+        }
+    }
+
+    pub fn toggle(&mut self) -> bool {
+        self.is_paused ^= true;
+        self.advances = 0;
+        self.is_paused
+    }
+
+    // The first press only pauses, each one after that runs one more frame
+    pub fn frame_advance(&mut self) {
+        if self.is_paused {
+            self.advances += 1;
+        }else {
+            self.is_paused = true;
+        }
+    }
+
+    pub fn frame_done(&mut self) {
+        self.is_frame_done = true;
+    }
+
+    // Called before each step, false while paused at the end of a frame
+    pub fn should_run(&mut self) -> bool {
+        if self.is_paused && self.is_frame_done {
+            if self.advances == 0 {
+                return false;
+            }
+            self.advances -= 1;
+        }
+        self.is_frame_done = false;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_ratio_follows_speed() {
+        let mut pacer = Pacer::new(Duration::from_millis(60), 0.005, 22050.0);
+        let target = pacer.target_samples() * BYTES_PER_SAMPLE;
+        assert_eq!(pacer.output_ratio(target), Some(1.0));
+        pacer.speed = 0.5;
+        assert_eq!(pacer.output_ratio(target), Some(2.0));
+        pacer.speed = 2.0;
+        assert_eq!(pacer.output_ratio(target), None);
+        pacer.speed = f64::INFINITY;
+        assert_eq!(pacer.output_ratio(target), None);
+        assert_eq!(pacer.frame_time(), Duration::ZERO);
+    }
+
+    #[test]
+    fn resample_joins_buffers() {
+        // Same rate, the output is the input one frame late
+        let mut resampler = Resampler::new();
+        assert_eq!(resampler.resample(&[1.0, -1.0, 2.0, -2.0], 1.0), [0.0, 0.0, 1.0, -1.0]);
+        assert_eq!(resampler.resample(&[3.0, -3.0], 1.0), [2.0, -2.0]);
+
+        // Twice the rate interpolates halfway frames, including across the buffer boundary
+        let mut resampler = Resampler::new();
+        assert_eq!(resampler.resample(&[2.0, 4.0], 2.0), [0.0, 0.0, 1.0, 2.0]);
+        assert_eq!(resampler.resample(&[4.0, 8.0], 2.0), [2.0, 4.0, 3.0, 6.0]);
+    }
+
+    #[test]
+    fn resampled_length_follows_ratio() {
+        for ratio in [0.25, 0.995, 1.005, 4.0] {
+            let mut resampler = Resampler::new();
+            let frames: usize = (0..100).map(|_| resampler.resample(&[0.0; 2*367], ratio).len() / 2).sum();
+            let expected = 100.0 * 367.0 * ratio;
+            assert!((frames as f64 - expected).abs() <= 1.0, "ratio {}: {} frames", ratio, frames);
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crc32fast::hash as crc32;
+
+// Soft patches applied to the rom bytes before the cartridge is built
+pub const EXTENSIONS: [&str; 3] = ["ips", "ups", "bps"];
+
+// Far more than any cartridge, sizes read from a corrupt or hostile patch are refused past this
+const MAX_TARGET_SIZE: usize = 64 << 20;
+
+// Patch files named after the rom, "game.gb" or "game.zip" look for "game.ips" / "game.ups" / "game.bps"
+pub fn find_patches(base_path: &Path) -> Vec<PathBuf> {
+    EXTENSIONS.iter()
+        .map(|ext| base_path.with_extension(ext))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+pub fn apply_file(rom: Vec<u8>, path: &Path) -> Result<Vec<u8>, String> {
+    let patch = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    apply(rom, &patch).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+// The format is picked from the magic bytes rather than the extension
+pub fn apply(rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    }else if patch.starts_with(b"UPS1") {
+        apply_ups(&rom, patch)
+    }else if patch.starts_with(b"BPS1") {
+        apply_bps(&rom, patch)
+    }else {
+        Err("not an IPS, UPS or BPS patch".to_string())
+    }
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        PatchReader{data, pos}
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.data.get(self.pos..).and_then(|rest| rest.get(..len)).ok_or("patch is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, len: usize) -> Result<usize, String> {
+        Ok(self.bytes(len)?.iter().fold(0, |acc, &b| (acc << 8) | b as usize))
+    }
+
+    // UPS / BPS variable length number, 7 bits per byte with the top bit marking the last one
+    fn number(&mut self) -> Result<usize, String> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let b = self.byte()?;
+            value = value.checked_add((b & 0x7F) as usize * shift).ok_or("patch number overflows")?;
+            if (b & 0x80) != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(128).ok_or("patch number overflows")?;
+            value += shift;
+        }
+    }
+}
+
+fn apply_ips(mut rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = PatchReader::new(patch, 5);
+    loop {
+        let offset = reader.bytes(3)?;
+        if offset == b"EOF" {
+            break;
+        }
+        let offset = offset.iter().fold(0, |acc, &b| (acc << 8) | b as usize);
+        let size = reader.be(2)?;
+        // A size of 0 is a run of one repeated byte
+        let (size, run) = if size == 0 {(reader.be(2)?, Some(reader.byte()?))} else {(size, None)};
+        if rom.len() < offset + size {
+            rom.resize(offset + size, 0x00);
+        }
+        match run {
+            Some(val) => rom[offset..offset + size].fill(val),
+            None => rom[offset..offset + size].copy_from_slice(reader.bytes(size)?),
+        }
+    }
+    // Optional truncation extension
+    if let Ok(len) = reader.be(3) {
+        rom.truncate(len);
+    }
+    Ok(rom)
+}
+
+// Last 12 bytes of UPS and BPS patches, source, target and patch crc32
+fn footer(patch: &[u8]) -> Result<(u32, u32), String> {
+    if patch.len() < 16 {
+        return Err("patch is truncated".to_string());
+    }
+    let crc = |offset: usize| u32::from_le_bytes(patch[offset..offset + 4].try_into().unwrap());
+    let end = patch.len() - 12;
+    if crc32(&patch[..end + 8]) != crc(end + 8) {
+        return Err("patch checksum mismatch, the patch file is corrupt".to_string());
+    }
+    Ok((crc(end), crc(end + 4)))
+}
+
+fn check_source(rom: &[u8], size: usize, crc: u32) -> Result<(), String> {
+    if rom.len() != size || crc32(rom) != crc {
+        return Err(format!("rom checksum mismatch, expected {:08X} but the rom is {:08X}", crc, crc32(rom)));
+    }
+    Ok(())
+}
+
+fn check_target_size(size: usize) -> Result<(), String> {
+    if size > MAX_TARGET_SIZE {
+        return Err(format!("patched rom would be {} bytes", size));
+    }
+    Ok(())
+}
+
+fn check_target(target: &[u8], crc: u32) -> Result<(), String> {
+    if crc32(target) != crc {
+        return Err(format!("patched rom checksum mismatch, expected {:08X} but got {:08X}", crc, crc32(target)));
+    }
+    Ok(())
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let (source_crc, target_crc) = footer(patch)?;
+    let end = patch.len() - 12;
+    let mut reader = PatchReader::new(&patch[..end], 4);
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    check_source(rom, source_size, source_crc)?;
+    check_target_size(target_size)?;
+
+    // Hunks skip unchanged bytes then xor the source until a 0 byte
+    let mut target = rom.to_vec();
+    target.resize(target_size, 0x00);
+    let mut pos = 0usize;
+    while reader.pos < end {
+        pos = pos.saturating_add(reader.number()?);
+        loop {
+            let xor = reader.byte()?;
+            if xor == 0 {
+                pos = pos.saturating_add(1);
+                break;
+            }
+            if let Some(b) = target.get_mut(pos) {
+                *b ^= xor;
+            }
+            pos = pos.saturating_add(1);
+        }
+    }
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let (source_crc, target_crc) = footer(patch)?;
+    let end = patch.len() - 12;
+    let mut reader = PatchReader::new(&patch[..end], 4);
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+    check_source(rom, source_size, source_crc)?;
+    check_target_size(target_size)?;
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+    // Copy offsets are signed and relative to the end of the previous copy of the same kind
+    let relative = |offset: usize, reader: &mut PatchReader| -> Result<usize, String> {
+        let data = reader.number()?;
+        let delta = data >> 1;
+        let offset = if (data & 1) != 0 {offset.checked_sub(delta)} else {offset.checked_add(delta)};
+        offset.ok_or("patch copy offset out of range".to_string())
+    };
+    while reader.pos < end {
+        let data = reader.number()?;
+        let len = (data >> 2) + 1;
+        // Checked before anything is copied so a bad length can't grow the output past target_size
+        if len > target_size - target.len() {
+            return Err("patch writes past the end of the output".to_string());
+        }
+        match data & 0x03 {
+            // Source read, the bytes at the same position in the rom
+            0 => {
+                let pos = target.len();
+                target.extend_from_slice(rom.get(pos..pos + len).ok_or("patch reads past the end of the rom")?);
+            }
+            // Target read, bytes stored in the patch
+            1 => target.extend_from_slice(reader.bytes(len)?),
+            // Source copy, bytes from anywhere in the rom
+            2 => {
+                source_offset = relative(source_offset, &mut reader)?;
+                let bytes = rom.get(source_offset..).and_then(|rest| rest.get(..len));
+                target.extend_from_slice(bytes.ok_or("patch reads past the end of the rom")?);
+                source_offset += len;
+            }
+            // Target copy may overlap what it is writing, so it goes a byte at a time
+            _ => {
+                target_offset = relative(target_offset, &mut reader)?;
+                for _ in 0..len {
+                    let b = *target.get(target_offset).ok_or("patch copies past the end of the output")?;
+                    target.push(b);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_size {
+        return Err(format!("patched rom is {} bytes instead of {}", target.len(), target_size));
+    }
+    check_target(&target, target_crc)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom() -> Vec<u8> {
+        (0..64).map(|i| i as u8).collect()
+    }
+
+    fn ips(records: &[u8]) -> Vec<u8> {
+        [b"PATCH".as_slice(), records, b"EOF"].concat()
+    }
+
+    fn number(data: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let b = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                data.push(b | 0x80);
+                return;
+            }
+            data.push(b);
+            value -= 1;
+        }
+    }
+
+    // Magic, sizes and body followed by the source, target and patch crc32
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    fn ups(source: &[u8], target: &[u8], hunks: &[(usize, &[u8])]) -> Vec<u8> {
+        let mut patch = b"UPS1".to_vec();
+        number(&mut patch, source.len());
+        number(&mut patch, target.len());
+        for &(skip, xor) in hunks {
+            number(&mut patch, skip);
+            patch.extend_from_slice(xor);
+            patch.push(0);
+        }
+        with_footer(patch, source, target)
+    }
+
+    enum Bps<'a> {
+        SourceRead(usize),
+        TargetRead(&'a [u8]),
+        SourceCopy(usize, isize),
+        TargetCopy(usize, isize),
+    }
+
+    fn bps(source: &[u8], target: &[u8], actions: &[Bps]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        number(&mut patch, source.len());
+        number(&mut patch, target.len());
+        number(&mut patch, 0);
+        let offset = |patch: &mut Vec<u8>, delta: isize| number(patch, (delta.unsigned_abs() << 1) | (delta < 0) as usize);
+        for action in actions {
+            match *action {
+                Bps::SourceRead(len) => number(&mut patch, (len - 1) << 2),
+                Bps::TargetRead(bytes) => {
+                    number(&mut patch, ((bytes.len() - 1) << 2) | 1);
+                    patch.extend_from_slice(bytes);
+                }
+                Bps::SourceCopy(len, delta) => {
+                    number(&mut patch, ((len - 1) << 2) | 2);
+                    offset(&mut patch, delta);
+                }
+                Bps::TargetCopy(len, delta) => {
+                    number(&mut patch, ((len - 1) << 2) | 3);
+                    offset(&mut patch, delta);
+                }
+            }
+        }
+        with_footer(patch, source, target)
+    }
+
+    #[test]
+    fn ips_records() {
+        // Plain record at 2, then a run of 4 0xEE at 10
+        let patch = ips(&[0, 0, 2, 0, 2, 0xAA, 0xBB, 0, 0, 10, 0, 0, 0, 4, 0xEE]);
+        let mut expected = rom();
+        expected[2..4].copy_from_slice(&[0xAA, 0xBB]);
+        expected[10..14].fill(0xEE);
+        assert_eq!(apply(rom(), &patch).unwrap(), expected);
+    }
+
+    #[test]
+    fn ips_grows_and_truncates() {
+        let patch = ips(&[0, 0, 66, 0, 0, 0, 2, 0x55]);
+        let patched = apply(rom(), &patch).unwrap();
+        assert_eq!(patched.len(), 68);
+        assert_eq!(patched[64..], [0x00, 0x00, 0x55, 0x55]);
+
+        let mut patch = ips(&[0, 0, 0, 0, 1, 0xFF]);
+        patch.extend_from_slice(&[0, 0, 16]);
+        let patched = apply(rom(), &patch).unwrap();
+        assert_eq!(patched.len(), 16);
+        assert_eq!(patched[0], 0xFF);
+    }
+
+    #[test]
+    fn ips_truncated() {
+        // Record data cut short, run missing its byte, no EOF marker
+        assert!(apply(rom(), b"PATCH\x00\x00\x02\x00\x04\xAA\xBB").is_err());
+        assert!(apply(rom(), b"PATCH\x00\x00\x02\x00\x00\x00\x04").is_err());
+        assert!(apply(rom(), b"PATCH\x00\x00\x02\x00\x01\xAA").is_err());
+        assert!(apply(rom(), b"PATCH").is_err());
+    }
+
+    #[test]
+    fn ups_xor_runs() {
+        let source = rom();
+        let mut target = source.clone();
+        target[3] ^= 0x0F;
+        target[4] ^= 0xF0;
+        target.extend_from_slice(&[0x11, 0x22]);
+        // Skip 3, xor 2 bytes, then skip to the 2 bytes past the end of the source
+        let patch = ups(&source, &target, &[(3, &[0x0F, 0xF0]), (58, &[0x11, 0x22])]);
+        assert_eq!(apply(source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn ups_checksums() {
+        let source = rom();
+        let mut target = source.clone();
+        target[0] = 0x80;
+        let patch = ups(&source, &target, &[(0, &[0x80])]);
+
+        let mut other = source.clone();
+        other[63] = 0;
+        assert!(apply(other, &patch).unwrap_err().starts_with("rom checksum mismatch"));
+
+        let bad_target = ups(&source, &target, &[(0, &[0x81])]);
+        assert!(apply(source.clone(), &bad_target).unwrap_err().starts_with("patched rom checksum mismatch"));
+
+        let mut corrupt = patch.clone();
+        corrupt[6] ^= 0x01;
+        assert!(apply(source, &corrupt).unwrap_err().starts_with("patch checksum mismatch"));
+    }
+
+    #[test]
+    fn bps_actions() {
+        let source = rom();
+        let mut target = source[..4].to_vec();
+        target.extend_from_slice(&[0xA0, 0xA1]);
+        target.extend_from_slice(&source[32..36]);
+        // Target copy overlapping its own output repeats the last two bytes
+        target.extend_from_slice(&[0x22, 0x23, 0x22, 0x23, 0x22]);
+        let patch = bps(&source, &target, &[
+            Bps::SourceRead(4),
+            Bps::TargetRead(&[0xA0, 0xA1]),
+            Bps::SourceCopy(4, 32),
+            Bps::TargetCopy(5, 8),
+        ]);
+        assert_eq!(apply(source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_relative_offsets() {
+        let source = rom();
+        // The second source copy goes back from the end of the first, the target copy starts from 0
+        let target = vec![10, 11, 4, 5, 4, 5];
+        let patch = bps(&source, &target, &[Bps::SourceCopy(2, 10), Bps::SourceCopy(2, -8), Bps::TargetCopy(2, 2)]);
+        assert_eq!(apply(source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn bps_checksums() {
+        let source = rom();
+        let target = vec![0x01, 0x02];
+        let patch = bps(&source, &target, &[Bps::TargetRead(&[0x01, 0x02])]);
+
+        assert!(apply(source[1..].to_vec(), &patch).unwrap_err().starts_with("rom checksum mismatch"));
+
+        let bad_target = bps(&source, &target, &[Bps::TargetRead(&[0x01, 0x03])]);
+        assert!(apply(source.clone(), &bad_target).unwrap_err().starts_with("patched rom checksum mismatch"));
+
+        let mut corrupt = patch.clone();
+        corrupt[8] ^= 0x01;
+        assert!(apply(source, &corrupt).unwrap_err().starts_with("patch checksum mismatch"));
+    }
+
+    #[test]
+    fn bps_writes_past_target_size() {
+        // Every action is refused before it copies anything once it would pass the 2 byte target
+        let source = rom();
+        let target = vec![0x01, 0x02];
+        for action in [Bps::SourceRead(3), Bps::TargetRead(&[1, 2, 3]), Bps::SourceCopy(3, 0)] {
+            let patch = bps(&source, &target, &[action]);
+            assert_eq!(apply(source.clone(), &patch).unwrap_err(), "patch writes past the end of the output");
+        }
+        let patch = bps(&source, &target, &[Bps::TargetRead(&[1]), Bps::TargetCopy(usize::MAX >> 3, 0)]);
+        assert_eq!(apply(source.clone(), &patch).unwrap_err(), "patch writes past the end of the output");
+
+        let mut huge = b"BPS1".to_vec();
+        number(&mut huge, source.len());
+        number(&mut huge, usize::MAX >> 8);
+        number(&mut huge, 0);
+        let huge = with_footer(huge, &source, &target);
+        assert!(apply(source, &huge).unwrap_err().starts_with("patched rom would be"));
+    }
+}
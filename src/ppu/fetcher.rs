@@ -2,6 +2,7 @@ use std::collections::VecDeque;
 use crate::ppu::pixel::Pixel;
 use crate::ppu::oam::Obj;
 use crate::bus::Bus;
+use crate::savestate::{savestate_enum, savestate_fields};
 
 #[derive(Debug)]
 pub struct Fetcher{
@@ -243,4 +244,13 @@ pub fn display_tiles(bus: &mut Bus) {
         }
         println!();
     }
-}
\ No newline at end of file
+}
+
+savestate_enum!(FetcherState {
+    ReadTileId, ReadTileData0, ReadTileData1, PushToFifo,
+    ReadSpriteId, ReadSpriteFlags, ReadSpriteData0, ReadSpriteData1, MixInFifo,
+});
+savestate_fields!(Fetcher {
+    state, dots, fifo, mapaddr, xoffset, tiledataaddr, tileline, tileid, tiledata0, tiledata1, tileidsigned,
+    obj, objoffset, objtileline, objflags, objoamindex, divider, is_disabled,
+});
\ No newline at end of file
@@ -9,11 +9,13 @@ use crate::ppu::oam::Oam;
 
 
 use crate::bus::Bus;
+use crate::savestate::{savestate_enum, savestate_fields};
 
 pub const WHITE: u8 = 0xFF;
 pub const LIGHT_GRAY: u8 = 0xA9;
 pub const DARK_GRAY: u8 = 0x54;
 pub const BLACK: u8 = 0x00;
+pub const GRAY_PALETTE: [[u8; 3]; 4] = [[WHITE; 3], [LIGHT_GRAY; 3], [DARK_GRAY; 3], [BLACK; 3]];
 
 pub struct Ppu {
     pub state: PpuState,
@@ -27,6 +29,7 @@ pub struct Ppu {
     pub is_window: bool,
     pub framebuffer: [u8; 3*160*144],
     pub entered_vblank: bool,
+    pub palette: [[u8; 3]; 4], // rgb of the four shades, not machine state
 
 }
 pub enum PpuState {
@@ -52,6 +55,7 @@ impl Ppu {
 
             framebuffer: [0; 3*160*144],
             entered_vblank: false,
+            palette: GRAY_PALETTE,
 
         }
     }
@@ -160,17 +164,11 @@ impl Ppu {
                         palette = bus.obp0;
                     }
                     let colorid = (palette & (0x03 << (2*index))) >> (2*index); 
-                    let color = match colorid & 0x03 {
-                        0x00 => WHITE,
-                        0x01 => LIGHT_GRAY,
-                        0x02 => DARK_GRAY,
-                        0x03 => BLACK,
-                        _ => panic!("This will never be reached, trust me"),
-                    };
-
-                    self.framebuffer[(3 * ((160 * bus.ly as usize) + self.xpos as usize)) + 0] = color;
-                    self.framebuffer[(3 * ((160 * bus.ly as usize) + self.xpos as usize)) + 1] = color;
-                    self.framebuffer[(3 * ((160 * bus.ly as usize) + self.xpos as usize)) + 2] = color;
+                    let color = self.palette[(colorid & 0x03) as usize];
+
+                    self.framebuffer[(3 * ((160 * bus.ly as usize) + self.xpos as usize)) + 0] = color[0];
+                    self.framebuffer[(3 * ((160 * bus.ly as usize) + self.xpos as usize)) + 1] = color[1];
+                    self.framebuffer[(3 * ((160 * bus.ly as usize) + self.xpos as usize)) + 2] = color[2];
                     self.xpos += 1;
                 }
                 if self.xpos == 160 {
@@ -263,4 +261,7 @@ impl Ppu {
     }
 
     
-}
\ No newline at end of file
+}
+
+savestate_enum!(PpuState {OamSearch, PixelTransfer, HBlank, VBlank});
+savestate_fields!(Ppu {state, dots, xpos, fetcher, oam, to_drop, is_window, framebuffer, entered_vblank});
\ No newline at end of file
@@ -1,4 +1,5 @@
 use crate::bus::Bus;
+use crate::savestate::{savestate_enum, savestate_fields};
 
 pub struct Oam {
     pub state: OamState,
@@ -63,4 +64,8 @@ impl Oam {
         }
         return self.index >= 40
     }
-}
\ No newline at end of file
+}
+
+savestate_enum!(OamState {ReadObjY, ReadObjX});
+savestate_fields!(Oam {state, index, curr_obj, obj_table});
+savestate_fields!(Obj {ypos, xpos, oamaddr, is_fetched});
\ No newline at end of file
@@ -1,3 +1,5 @@
+use crate::savestate::savestate_fields;
+
 #[derive(Copy,Clone, Debug)]
 pub struct Pixel {
     pub color: u8,
@@ -47,4 +49,6 @@ impl Default for Pixel {
             bgpriority: None,
         }
     }
-}
\ No newline at end of file
+}
+
+savestate_fields!(Pixel {color, palette, bgpriority});
\ No newline at end of file
@@ -0,0 +1,242 @@
+use crate::bus::Bus;
+
+// Searchable memory is wram, hram then every bank of cartridge sram laid end to end,
+// candidates are indices into that space.
+const WRAM_SIZE: usize = 0x2000;
+const HRAM_SIZE: usize = 0x007F;
+const SRAM_START: usize = WRAM_SIZE + HRAM_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Word, // little endian, like the cpu
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Equal(u16),
+    NotEqual(u16),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+    pub index: usize,
+    pub value: u16,
+    pub previous: u16,
+}
+
+#[derive(Debug)]
+pub struct RamSearch {
+    pub width: Width,
+    pub snapshot: Vec<u8>,
+    pub candidates: Vec<usize>,
+}
+
+impl RamSearch {
+    // Starts a new search with every location as a candidate
+    pub fn new(bus: &Bus, width: Width) -> Self {
+        let snapshot = memory(bus);
+        let candidates = (0..snapshot.len()).filter(|&i| fits(i, width, snapshot.len())).collect();
+        RamSearch{width, snapshot, candidates}
+    }
+
+    // Keeps the candidates matching the filter and takes a new snapshot to compare against next time
+    pub fn filter(&mut self, bus: &Bus, filter: Filter) {
+        let current = memory(bus);
+        if current.len() != self.snapshot.len() {
+            // Only happens if the cartridge changed, nothing left to compare
+            self.candidates.clear();
+        }
+        let width = self.width;
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&i| {
+            let (now, previous) = (value(&current, i, width), value(snapshot, i, width));
+            match filter {
+                Filter::Equal(val) => now == val,
+                Filter::NotEqual(val) => now != val,
+                Filter::Changed => now != previous,
+                Filter::Unchanged => now == previous,
+                Filter::Increased => now > previous,
+                Filter::Decreased => now < previous,
+            }
+        });
+        self.snapshot = current;
+    }
+
+    // Current and snapshot values of the first max candidates
+    pub fn results(&self, bus: &Bus, max: usize) -> Vec<Candidate> {
+        let current = memory(bus);
+        self.candidates.iter()
+            .filter(|&&i| fits(i, self.width, current.len()))
+            .take(max)
+            .map(|&index| Candidate{
+                index,
+                value: value(&current, index, self.width),
+                previous: value(&self.snapshot, index, self.width),
+            })
+            .collect()
+    }
+}
+
+fn memory(bus: &Bus) -> Vec<u8> {
+    let mut memory = Vec::with_capacity(SRAM_START + bus.cart.sram.len());
+    memory.extend_from_slice(&bus.wram0);
+    memory.extend_from_slice(&bus.wramn);
+    memory.extend_from_slice(&bus.hram);
+    memory.extend_from_slice(&bus.cart.sram);
+    memory
+}
+
+// Words don't straddle two regions or the end of an sram bank
+fn fits(index: usize, width: Width, len: usize) -> bool {
+    match width {
+        Width::Byte => index < len,
+        Width::Word => index + 1 < len && index + 1 != SRAM_START && index + 1 != WRAM_SIZE
+            && (index < SRAM_START || (index + 1 - SRAM_START) & 0x1FFF != 0),
+    }
+}
+
+fn value(memory: &[u8], index: usize, width: Width) -> u16 {
+    match width {
+        Width::Byte => memory[index] as u16,
+        Width::Word => u16::from_le_bytes([memory[index], memory[index + 1]]),
+    }
+}
+
+// Cpu address of a candidate and the sram bank it is in, if any
+pub fn address(index: usize) -> (u16, Option<usize>) {
+    match index {
+        0..WRAM_SIZE => (0xC000 + index as u16, None),
+        WRAM_SIZE..SRAM_START => (0xFF80 + (index - WRAM_SIZE) as u16, None),
+        _ => {
+            let offset = index - SRAM_START;
+            (0xA000 + (offset & 0x1FFF) as u16, Some(offset >> 13))
+        }
+    }
+}
+
+// "C0A4" for wram / hram, "02:A010" for sram bank 2
+pub fn describe(index: usize) -> String {
+    match address(index) {
+        (addr, None) => format!("{:04X}", addr),
+        (addr, Some(bank)) => format!("{:02X}:{:04X}", bank, addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    // MBC1 cartridge with 32KiB of sram, four banks after wram and hram
+    fn bus() -> Bus {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x03;
+        Bus::new(Cartridge::new(rom, Vec::new()))
+    }
+
+    const LEN: usize = SRAM_START + 0x8000;
+
+    #[test]
+    fn bytes_fit_everywhere() {
+        assert!(fits(0, Width::Byte, LEN));
+        assert!(fits(WRAM_SIZE - 1, Width::Byte, LEN));
+        assert!(fits(SRAM_START - 1, Width::Byte, LEN));
+        assert!(fits(LEN - 1, Width::Byte, LEN));
+        assert!(!fits(LEN, Width::Byte, LEN));
+    }
+
+    #[test]
+    fn words_dont_straddle_regions() {
+        assert!(fits(WRAM_SIZE - 2, Width::Word, LEN));
+        assert!(!fits(WRAM_SIZE - 1, Width::Word, LEN)); // DFFF and FF80
+        assert!(fits(WRAM_SIZE, Width::Word, LEN));
+        assert!(fits(SRAM_START - 2, Width::Word, LEN));
+        assert!(!fits(SRAM_START - 1, Width::Word, LEN)); // FFFE and sram
+        assert!(fits(SRAM_START, Width::Word, LEN));
+        assert!(fits(SRAM_START + 0x1FFE, Width::Word, LEN));
+        assert!(!fits(SRAM_START + 0x1FFF, Width::Word, LEN)); // end of bank 0 and start of bank 1
+        assert!(fits(SRAM_START + 0x2000, Width::Word, LEN));
+        assert!(!fits(LEN - 1, Width::Word, LEN));
+        // Without sram the last hram byte is the end of memory
+        assert!(!fits(SRAM_START - 1, Width::Word, SRAM_START));
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(address(0), (0xC000, None));
+        assert_eq!(address(WRAM_SIZE - 1), (0xDFFF, None));
+        assert_eq!(address(WRAM_SIZE), (0xFF80, None));
+        assert_eq!(address(SRAM_START - 1), (0xFFFE, None));
+        assert_eq!(address(SRAM_START), (0xA000, Some(0)));
+        assert_eq!(address(SRAM_START + 0x1FFF), (0xBFFF, Some(0)));
+        assert_eq!(address(SRAM_START + 0x2001), (0xA001, Some(1)));
+        assert_eq!(address(LEN - 1), (0xBFFF, Some(3)));
+        assert_eq!(describe(WRAM_SIZE + 4), "FF84");
+        assert_eq!(describe(SRAM_START + 0x4010), "02:A010");
+    }
+
+    #[test]
+    fn new_search_candidates() {
+        let bus = bus();
+        assert_eq!(RamSearch::new(&bus, Width::Byte).candidates.len(), LEN);
+        // Every word but the ones ending on a region or bank boundary: wram, hram and four sram banks
+        assert_eq!(RamSearch::new(&bus, Width::Word).candidates.len(), LEN - 6);
+    }
+
+    #[test]
+    fn byte_filters() {
+        let mut bus = bus();
+        bus.writeu8(0xC000, 5);
+        bus.writeu8(0xFF80, 5);
+        bus.cart.sram[0x2000] = 5;
+        let mut search = RamSearch::new(&bus, Width::Byte);
+        search.filter(&bus, Filter::Equal(5));
+        assert_eq!(search.candidates, [0, WRAM_SIZE, SRAM_START + 0x2000]);
+
+        bus.writeu8(0xC000, 6);
+        bus.writeu8(0xFF80, 4);
+        search.filter(&bus, Filter::Changed);
+        assert_eq!(search.candidates, [0, WRAM_SIZE]);
+        search.filter(&bus, Filter::Unchanged);
+        assert_eq!(search.candidates, [0, WRAM_SIZE]);
+
+        bus.writeu8(0xC000, 7);
+        bus.writeu8(0xFF80, 3);
+        let mut decreased = RamSearch{width: search.width, snapshot: search.snapshot.clone(), candidates: search.candidates.clone()};
+        search.filter(&bus, Filter::Increased);
+        assert_eq!(search.candidates, [0]);
+        decreased.filter(&bus, Filter::Decreased);
+        assert_eq!(decreased.candidates, [WRAM_SIZE]);
+        decreased.filter(&bus, Filter::NotEqual(3));
+        assert!(decreased.candidates.is_empty());
+
+        let results = search.results(&bus, 10);
+        assert_eq!(results, [Candidate{index: 0, value: 7, previous: 7}]);
+    }
+
+    #[test]
+    fn word_filters_skip_boundaries() {
+        let mut bus = bus();
+        // 0x1234 inside wram, and the same bytes split across DFFF / FF80 and the end of sram bank 0
+        bus.writeu8(0xC010, 0x34);
+        bus.writeu8(0xC011, 0x12);
+        bus.writeu8(0xDFFF, 0x34);
+        bus.writeu8(0xFF80, 0x12);
+        bus.cart.sram[0x1FFF] = 0x34;
+        bus.cart.sram[0x2000] = 0x12;
+        let mut search = RamSearch::new(&bus, Width::Word);
+        search.filter(&bus, Filter::Equal(0x1234));
+        assert_eq!(search.candidates, [0x10]);
+
+        bus.writeu8(0xC011, 0x13);
+        search.filter(&bus, Filter::Increased);
+        let results = search.results(&bus, 10);
+        assert_eq!(results, [Candidate{index: 0x10, value: 0x1334, previous: 0x1334}]);
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::VecDeque;
+
+// Ring buffer of machine snapshots for rewinding. Only the newest snapshot is kept whole,
+// older ones are stored as deltas that turn a snapshot into the one taken before it.
+#[derive(Debug)]
+pub struct Rewind {
+    pub interval: u32, // frames between snapshots
+    pub capacity: usize, // snapshots kept, the oldest are dropped first
+    pub newest: Option<Vec<u8>>,
+    pub deltas: VecDeque<Vec<u8>>,
+    pub frames: u32, // frames since the last snapshot
+}
+
+impl Rewind {
+    pub fn new(interval: u32, capacity: usize) -> Self {
+        Rewind{
+            interval,
+            capacity,
+            newest: None,
+            deltas: VecDeque::new(),
+            frames: 0,
+        }
+    }
+
+    // Called once per frame, snapshot is only run every interval frames
+    pub fn frame(&mut self, snapshot: impl FnOnce() -> Vec<u8>) {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+        let current = snapshot();
+        if let Some(previous) = self.newest.take() {
+            self.deltas.push_back(encode(&current, &previous));
+            if self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.newest = Some(current);
+    }
+
+    // Returns the newest snapshot and steps back to the one before it,
+    // the oldest one is returned again once there is nothing older
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+        self.newest = Some(match self.deltas.pop_back() {
+            Some(delta) => decode(&newest, &delta),
+            None => newest.clone(),
+        });
+        self.frames = 0;
+        Some(newest)
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.frames = 0;
+    }
+}
+
+// Deltas are the xor of two snapshots with runs of zeroes left out:
+// target length, then pairs of zero run length and literal length followed by the literal bytes.
+// Most of the machine doesn't change between snapshots so the runs are long.
+fn encode(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| from.get(i).copied().unwrap_or(0) ^ to[i];
+    let mut delta = Vec::new();
+    write_len(&mut delta, to.len());
+    let mut i = 0;
+    while i < to.len() {
+        let zeroes = (i..to.len()).take_while(|&j| xor(j) == 0).count();
+        i += zeroes;
+        // Short zero runs inside a literal are cheaper kept in it than split into a new pair
+        let start = i;
+        while (i..to.len().min(i + 4)).any(|j| xor(j) != 0) {
+            i += 1;
+        }
+        write_len(&mut delta, zeroes);
+        write_len(&mut delta, i - start);
+        delta.extend((start..i).map(xor));
+    }
+    delta
+}
+
+fn decode(from: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_len(delta, &mut pos);
+    let mut to: Vec<u8> = (0..len).map(|i| from.get(i).copied().unwrap_or(0)).collect();
+    let mut i = 0;
+    while pos < delta.len() {
+        i += read_len(delta, &mut pos);
+        let literal = read_len(delta, &mut pos);
+        for byte in &delta[pos..pos + literal] {
+            to[i] ^= byte;
+            i += 1;
+        }
+        pos += literal;
+    }
+    to
+}
+
+// Lengths are stored 7 bits at a time, low bits first, with bit 7 set on all but the last byte
+fn write_len(data: &mut Vec<u8>, mut len: usize) {
+    while len >= 0x80 {
+        data.push((len as u8) | 0x80);
+        len >>= 7;
+    }
+    data.push(len as u8);
+}
+
+fn read_len(data: &[u8], pos: &mut usize) -> usize {
+    let mut len = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        len |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return len;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(from: &[u8], to: &[u8]) -> Vec<u8> {
+        let delta = encode(from, to);
+        assert_eq!(decode(from, &delta), to);
+        delta
+    }
+
+    #[test]
+    fn identical_snapshots() {
+        let snapshot: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        // Just the length and one pair for the zero run
+        assert_eq!(round_trip(&snapshot, &snapshot), [0xE8, 0x07, 0xE8, 0x07, 0x00]);
+    }
+
+    #[test]
+    fn all_different() {
+        let from = vec![0x00; 300];
+        let to = vec![0xFF; 300];
+        let delta = round_trip(&from, &to);
+        assert_eq!(delta.len(), 2 + 1 + 2 + 300);
+    }
+
+    #[test]
+    fn long_runs() {
+        // Zero runs and literals past 127 bytes take two varint bytes, past 16383 three
+        let from = vec![0x55; 40000];
+        let mut to = from.clone();
+        to[200..400].fill(0xAA);
+        to[20000] ^= 0x01;
+        round_trip(&from, &to);
+        assert_eq!(read_len(&[0xFF, 0xFF, 0x01], &mut 0), 0x7FFF);
+        let mut data = Vec::new();
+        write_len(&mut data, 16384);
+        assert_eq!(data, [0x80, 0x80, 0x01]);
+    }
+
+    #[test]
+    fn length_mismatch() {
+        let short = vec![1, 2, 3];
+        let long = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        round_trip(&short, &long);
+        round_trip(&long, &short);
+        round_trip(&[], &long);
+        round_trip(&long, &[]);
+    }
+
+    #[test]
+    fn pop_steps_back_through_snapshots() {
+        let mut rewind = Rewind::new(1, 10);
+        for i in 0..3u8 {
+            rewind.frame(|| vec![i; 100]);
+        }
+        assert_eq!(rewind.pop(), Some(vec![2; 100]));
+        assert_eq!(rewind.pop(), Some(vec![1; 100]));
+        assert_eq!(rewind.pop(), Some(vec![0; 100]));
+        assert_eq!(rewind.pop(), Some(vec![0; 100]));
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::bus::Bus;
+use crate::cheats::Cheats;
+use crate::cpu::Cpu;
+use crate::dma::Dma;
+use crate::movie::VERSION;
+use crate::ppu::Ppu;
+
+// Machine snapshots as a flat byte stream. Each component writes its fields in a fixed order,
+// snapshots are only read back by the same build with the same cartridge so there is no tagging.
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl StateWriter {
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+impl<'a> StateReader<'a> {
+    pub fn read<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let bytes = self.data.get(self.pos..self.pos + N).ok_or("state is truncated")?;
+        self.pos += N;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    // Every element takes at least a byte, so a longer length can only come from a corrupted state
+    fn read_len(&mut self) -> Result<usize, String> {
+        let mut len = 0usize;
+        len.load(self)?;
+        if len > self.remaining() {
+            return Err(format!("invalid length {} in state", len));
+        }
+        Ok(len)
+    }
+}
+
+// load fails on truncated or corrupted data and can leave the value partly overwritten,
+// load_machine checks the whole snapshot before touching the running machine
+pub trait Savestate {
+    fn save(&self, w: &mut StateWriter);
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String>;
+}
+
+// Implements Savestate for a struct by saving the listed fields in order
+macro_rules! savestate_fields {
+    ($type:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::savestate::Savestate for $type {
+            fn save(&self, w: &mut $crate::savestate::StateWriter) {
+                $($crate::savestate::Savestate::save(&self.$field, w);)*
+            }
+            fn load(&mut self, r: &mut $crate::savestate::StateReader) -> Result<(), String> {
+                $($crate::savestate::Savestate::load(&mut self.$field, r)?;)*
+                Ok(())
+            }
+        }
+    };
+}
+pub(crate) use savestate_fields;
+
+// Implements Savestate for an enum without fields by saving the variant index
+macro_rules! savestate_enum {
+    ($type:ident { $($variant:ident),* $(,)? }) => {
+        impl $crate::savestate::Savestate for $type {
+            fn save(&self, w: &mut $crate::savestate::StateWriter) {
+                let variants = [$($type::$variant),*];
+                let index = variants.iter().position(|variant| std::mem::discriminant(variant) == std::mem::discriminant(self)).unwrap() as u8;
+                $crate::savestate::Savestate::save(&index, w);
+            }
+            fn load(&mut self, r: &mut $crate::savestate::StateReader) -> Result<(), String> {
+                let mut index = 0u8;
+                $crate::savestate::Savestate::load(&mut index, r)?;
+                *self = [$($type::$variant),*].into_iter().nth(index as usize)
+                    .ok_or(format!("invalid {} {} in state", stringify!($type), index))?;
+                Ok(())
+            }
+        }
+    };
+}
+pub(crate) use savestate_enum;
+
+macro_rules! savestate_number {
+    ($($type:ty),*) => {
+        $(impl Savestate for $type {
+            fn save(&self, w: &mut StateWriter) {
+                w.write(&self.to_le_bytes());
+            }
+            fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+                *self = <$type>::from_le_bytes(r.read()?);
+                Ok(())
+            }
+        })*
+    };
+}
+savestate_number!(u8, i8, u16, u32, u64, usize, f32, f64);
+
+impl Savestate for bool {
+    fn save(&self, w: &mut StateWriter) {
+        w.write(&[*self as u8]);
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        *self = r.read::<1>()?[0] != 0;
+        Ok(())
+    }
+}
+
+impl<T: Savestate + Default> Savestate for Option<T> {
+    fn save(&self, w: &mut StateWriter) {
+        self.is_some().save(w);
+        if let Some(val) = self {
+            val.save(w);
+        }
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let mut is_some = false;
+        is_some.load(r)?;
+        *self = None;
+        if is_some {
+            let mut val = T::default();
+            val.load(r)?;
+            *self = Some(val);
+        }
+        Ok(())
+    }
+}
+
+impl Savestate for String {
+    fn save(&self, w: &mut StateWriter) {
+        self.len().save(w);
+        w.write(self.as_bytes());
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let mut bytes = Vec::<u8>::new();
+        bytes.load(r)?;
+        *self = String::from_utf8(bytes).map_err(|_| "invalid text in state")?;
+        Ok(())
+    }
+}
+
+impl<T: Savestate, const N: usize> Savestate for [T; N] {
+    fn save(&self, w: &mut StateWriter) {
+        self.iter().for_each(|val| val.save(w));
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.iter_mut().try_for_each(|val| val.load(r))
+    }
+}
+
+impl<T: Savestate + Default> Savestate for Vec<T> {
+    fn save(&self, w: &mut StateWriter) {
+        self.len().save(w);
+        self.iter().for_each(|val| val.save(w));
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let len = r.read_len()?;
+        self.clear();
+        self.resize_with(len, T::default);
+        self.iter_mut().try_for_each(|val| val.load(r))
+    }
+}
+
+impl<T: Savestate + Default> Savestate for VecDeque<T> {
+    fn save(&self, w: &mut StateWriter) {
+        self.len().save(w);
+        self.iter().for_each(|val| val.save(w));
+    }
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        let len = r.read_len()?;
+        self.clear();
+        self.resize_with(len, T::default);
+        self.iter_mut().try_for_each(|val| val.load(r))
+    }
+}
+
+// Cheats only go into the snapshot when they are passed in, loading one without them leaves the active ones as they are
+pub fn save_machine(cpu: &Cpu, bus: &Bus, ppu: &Ppu, dma: &Dma, cheats: Option<&Cheats>) -> Vec<u8> {
+    let mut w = StateWriter{data: Vec::new()};
+    cpu.save(&mut w);
+    bus.save(&mut w);
+    ppu.save(&mut w);
+    dma.save(&mut w);
+    cheats.is_some().save(&mut w);
+    if let Some(cheats) = cheats {
+        cheats.save(&mut w);
+    }
+    w.data
+}
+
+// The snapshot is read into scratch copies first, so a truncated or corrupted one is rejected
+// before anything in the running machine changes
+pub fn load_machine(data: &[u8], cpu: &mut Cpu, bus: &mut Bus, ppu: &mut Ppu, dma: &mut Dma, cheats: &mut Cheats) -> Result<(), String> {
+    let mut scratch_bus = Bus::new(bus.cart.scratch());
+    read_machine(data, &mut Cpu::new(), &mut scratch_bus, &mut Ppu::new(), &mut Dma::new(), &mut Cheats::new())?;
+    if read_machine(data, cpu, bus, ppu, dma, cheats)? {
+        cheats.apply(bus);
+    }
+    Ok(())
+}
+
+// Returns whether the snapshot had cheats
+fn read_machine(data: &[u8], cpu: &mut Cpu, bus: &mut Bus, ppu: &mut Ppu, dma: &mut Dma, cheats: &mut Cheats) -> Result<bool, String> {
+    let mut r = StateReader{data, pos: 0};
+    cpu.load(&mut r)?;
+    bus.load(&mut r)?;
+    ppu.load(&mut r)?;
+    dma.load(&mut r)?;
+    let mut has_cheats = false;
+    has_cheats.load(&mut r)?;
+    if has_cheats {
+        cheats.load(&mut r)?;
+    }
+    if r.remaining() != 0 {
+        return Err("state has trailing data".to_string());
+    }
+    Ok(has_cheats)
+}
+
+// State files: "QGBSTATE", emulator version length and text, rom crc32, then the snapshot.
+// Snapshots have no field tags so only states from the same version can be loaded.
+const STATE_MAGIC: &[u8; 8] = b"QGBSTATE";
+
+pub fn write_file(path: &Path, rom_crc: u32, snapshot: &[u8]) -> io::Result<()> {
+    let mut data = STATE_MAGIC.to_vec();
+    data.push(VERSION.len() as u8);
+    data.extend_from_slice(VERSION.as_bytes());
+    data.extend_from_slice(&rom_crc.to_le_bytes());
+    data.extend_from_slice(snapshot);
+    fs::write(path, data)
+}
+
+pub fn read_file(path: &Path, rom_crc: u32) -> Result<Vec<u8>, String> {
+    let data = fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let invalid = || format!("{}: not a state file", path.display());
+    if data.len() < 9 || &data[0..8] != STATE_MAGIC {
+        return Err(invalid());
+    }
+    let version_end = 9 + data[8] as usize;
+    let version = data.get(9..version_end).ok_or_else(invalid)?;
+    if version != VERSION.as_bytes() {
+        return Err(format!("{}: state is from version {}, this is {}", path.display(), String::from_utf8_lossy(version), VERSION));
+    }
+    let crc = data.get(version_end..version_end + 4).ok_or_else(invalid)?;
+    if u32::from_le_bytes(crc.try_into().unwrap()) != rom_crc {
+        return Err(format!("{}: state is from a different rom", path.display()));
+    }
+    Ok(data[version_end + 4..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::Cartridge;
+
+    fn machine() -> (Cpu, Bus, Ppu, Dma, Cheats) {
+        let mut rom = vec![0u8; 0x8000];
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        (Cpu::new(), Bus::new(Cartridge::new(rom, Vec::new())), Ppu::new(), Dma::new(), Cheats::new())
+    }
+
+    #[test]
+    fn machine_round_trip() {
+        let (mut cpu, mut bus, mut ppu, mut dma, mut cheats) = machine();
+        cpu.pc = 0x1234;
+        bus.wram0[5] = 0x42;
+        bus.cart.sram[7] = 0x99;
+        cheats.add("01FF34C0", "").unwrap();
+        let snapshot = save_machine(&cpu, &bus, &ppu, &dma, Some(&cheats));
+
+        let (mut cpu, mut bus, _, _, mut other_cheats) = machine();
+        load_machine(&snapshot, &mut cpu, &mut bus, &mut ppu, &mut dma, &mut other_cheats).unwrap();
+        assert_eq!(cpu.pc, 0x1234);
+        assert_eq!(bus.wram0[5], 0x42);
+        assert_eq!(bus.cart.sram[7], 0x99);
+        assert_eq!(other_cheats.list.len(), 1);
+    }
+
+    #[test]
+    fn bad_state_leaves_machine_alone() {
+        let (mut cpu, mut bus, mut ppu, mut dma, mut cheats) = machine();
+        bus.wram0[5] = 0x42;
+        let snapshot = save_machine(&cpu, &bus, &ppu, &dma, None);
+
+        let (mut cpu2, mut bus2, mut ppu2, mut dma2, _) = machine();
+        cpu2.pc = 0x1234;
+        bus2.hram[3] = 0x77;
+        let before = save_machine(&cpu2, &bus2, &ppu2, &dma2, None);
+        for len in [0, 1, snapshot.len() / 2, snapshot.len() - 1] {
+            assert!(load_machine(&snapshot[..len], &mut cpu2, &mut bus2, &mut ppu2, &mut dma2, &mut cheats).is_err());
+        }
+        let mut trailing = snapshot.clone();
+        trailing.push(0);
+        assert!(load_machine(&trailing, &mut cpu2, &mut bus2, &mut ppu2, &mut dma2, &mut cheats).is_err());
+        assert_eq!(save_machine(&cpu2, &bus2, &ppu2, &dma2, None), before);
+
+        load_machine(&snapshot, &mut cpu, &mut bus, &mut ppu, &mut dma, &mut cheats).unwrap();
+    }
+
+    #[test]
+    fn lengths_are_bounded() {
+        let mut data = u64::MAX.to_le_bytes().to_vec();
+        data.extend_from_slice(&[1, 2, 3]);
+        let mut bytes = Vec::<u8>::new();
+        assert!(bytes.load(&mut StateReader{data: &data, pos: 0}).is_err());
+
+        let data = [3, 0, 0, 0, 0, 0, 0, 0, 1, 2];
+        assert_eq!(StateReader{data: &data, pos: 0}.read::<16>(), Err("state is truncated".to_string()));
+        assert!(bytes.load(&mut StateReader{data: &data, pos: 0}).is_err());
+    }
+}
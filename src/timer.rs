@@ -1,3 +1,5 @@
+use crate::savestate::savestate_fields;
+
 
 #[derive(Debug)]
 pub struct Timer {
@@ -66,4 +68,6 @@ impl Timer {
         
         0xF8 | enable | freq
     }
-}
\ No newline at end of file
+}
+
+savestate_fields!(Timer {div, div_mask, tima, tma, is_tima_enable, is_tima_overflowed});
\ No newline at end of file
@@ -0,0 +1,212 @@
+use crate::apu::Apu;
+use crate::apu::noise::Noise;
+use crate::apu::square::Square;
+use crate::apu::wave::Wave;
+
+pub const WIDTH: usize = 320;
+pub const HEIGHT: usize = 256;
+
+const ROW_HEIGHT: usize = HEIGHT / 4;
+const SCOPE_WIDTH: usize = 128;
+const TEXT_X: usize = SCOPE_WIDTH + 8;
+
+const BACKGROUND: [u8; 3] = [0x10, 0x10, 0x18];
+const GRID: [u8; 3] = [0x30, 0x30, 0x40];
+const TEXT: [u8; 3] = [0xE0, 0xE0, 0xE0];
+const MUTED: [u8; 3] = [0x70, 0x70, 0x70];
+const TRACE: [[u8; 3]; 4] = [
+    [0x40, 0xE0, 0x60],
+    [0x40, 0xC0, 0xF0],
+    [0xF0, 0xC0, 0x40],
+    [0xF0, 0x60, 0x60],
+];
+
+// Draws a per channel oscilloscope of the dac outputs and the decoded channel registers into an RGB24 buffer
+pub struct Visualizer {
+    pub framebuffer: Vec<u8>,
+}
+
+impl Default for Visualizer {
+    fn default() -> Self {
+        Self{
+            framebuffer: vec![0; 3*WIDTH*HEIGHT],
+        }
+    }
+}
+
+impl Visualizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The scopes show the apu stems, so record_stems has to be on while the visualizer is open
+    pub fn draw(&mut self, apu: &Apu) {
+        for pixel in self.framebuffer.chunks_exact_mut(3) {
+            pixel.copy_from_slice(&BACKGROUND);
+        }
+        let muted = [apu.dbgch1, apu.dbgch2, apu.dbgch3, apu.dbgch4].map(|on| !on);
+        for ch in 0..4 {
+            let top = ch * ROW_HEIGHT;
+            self.draw_scope(top, &apu.stems[ch], if muted[ch] {MUTED} else {TRACE[ch]});
+            let lines = match ch {
+                0 => square_lines("CH1", &apu.ch1, true),
+                1 => square_lines("CH2", &apu.ch2, false),
+                2 => wave_lines(&apu.ch3),
+                _ => noise_lines(&apu.ch4),
+            };
+            for (i, line) in lines.iter().enumerate() {
+                let color = if muted[ch] {MUTED} else {TEXT};
+                self.draw_text(TEXT_X, top + 4 + 7*i, line, color);
+            }
+            if muted[ch] {
+                self.draw_text(SCOPE_WIDTH - 24, top + 4, "MUTE", TEXT);
+            }
+        }
+    }
+
+    fn put(&mut self, x: usize, y: usize, color: [u8; 3]) {
+        if x < WIDTH && y < HEIGHT {
+            let i = 3*(y*WIDTH + x);
+            self.framebuffer[i..i + 3].copy_from_slice(&color);
+        }
+    }
+
+    fn draw_scope(&mut self, top: usize, samples: &[f32], color: [u8; 3]) {
+        let mid = top + ROW_HEIGHT / 2;
+        for x in 0..SCOPE_WIDTH {
+            self.put(x, mid, GRID);
+            self.put(x, top + ROW_HEIGHT - 1, GRID);
+        }
+        if samples.is_empty() {
+            return;
+        }
+        // Show the most recent samples, one per column
+        let start = samples.len().saturating_sub(SCOPE_WIDTH);
+        let to_y = |sample: f32| {
+            let half = (ROW_HEIGHT / 2 - 2) as f32;
+            (mid as f32 - sample.clamp(-1.0, 1.0) * half) as usize
+        };
+        let mut prev_y = to_y(samples[start]);
+        for (x, &sample) in samples[start..].iter().enumerate() {
+            let y = to_y(sample);
+            for line_y in prev_y.min(y)..=prev_y.max(y) {
+                self.put(x, line_y, color);
+            }
+            prev_y = y;
+        }
+    }
+
+    fn draw_text(&mut self, x: usize, y: usize, text: &str, color: [u8; 3]) {
+        for (i, c) in text.chars().enumerate() {
+            let glyph = glyph(c.to_ascii_uppercase());
+            for row in 0..5 {
+                for col in 0..3 {
+                    if glyph & (1 << (14 - (row*3 + col))) != 0 {
+                        self.put(x + 4*i + col, y + row, color);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {"ON"} else {"OFF"}
+}
+
+fn panning(left: bool, right: bool) -> String {
+    format!("{}{}", if left {"L"} else {"-"}, if right {"R"} else {"-"})
+}
+
+fn square_lines(name: &str, ch: &Square, sweep: bool) -> Vec<String> {
+    const DUTY: [&str; 4] = ["12.5%", "25%", "50%", "75%"];
+    let hz = 131072 / (2048 - ch.freq as u32);
+    let mut lines = vec![
+        format!("{} SQUARE {} DAC {} {}", name, on_off(ch.enabled), on_off(ch.dac_enable), panning(ch.left_enable, ch.right_enable)),
+        format!("DUTY {} FREQ {} ({}HZ)", DUTY[ch.duty as usize & 0x03], ch.freq, hz),
+    ];
+    if sweep {
+        lines.push(format!("SWEEP {} P:{} {} S:{} SHADOW {}", on_off(ch.sweep_enable), ch.sweep_period, if ch.sweep_negate {"-"} else {"+"}, ch.sweep_shift, ch.freq_shadow));
+    }
+    lines.push(format!("ENV VOL {} {} P:{}", ch.envelope.current_vol, if ch.envelope.sweep_increase {"UP"} else {"DOWN"}, ch.envelope.period));
+    lines.push(format!("LEN {} {}", on_off(ch.length_counter.enabled), ch.length_counter.counter));
+    lines
+}
+
+fn wave_lines(ch: &Wave) -> Vec<String> {
+    const VOL: [&str; 4] = ["0%", "100%", "50%", "25%"];
+    let hz = 65536 / (2048 - ch.freq as u32);
+    let table: String = ch.wave_table.iter().map(|b| format!("{:02X}", b)).collect();
+    vec![
+        format!("CH3 WAVE {} DAC {} {}", on_off(ch.enabled), on_off(ch.dac_enable), panning(ch.left_enable, ch.right_enable)),
+        format!("VOL {} FREQ {} ({}HZ)", VOL[ch.vol as usize & 0x03], ch.freq, hz),
+        format!("POS {}", ch.table_index),
+        format!("LEN {} {}", on_off(ch.length_counter.enabled), ch.length_counter.counter),
+        table[..16].to_string(),
+        table[16..].to_string(),
+    ]
+}
+
+fn noise_lines(ch: &Noise) -> Vec<String> {
+    let hz = 4194304 / ((ch.base_divisor() as u32) << ch.shift_clock_freq);
+    vec![
+        format!("CH4 NOISE {} DAC {} {}", on_off(ch.enabled), on_off(ch.dac_enable), panning(ch.left_enable, ch.right_enable)),
+        format!("LFSR {} BIT {:04X}", if ch.width_mode {7} else {15}, ch.lsfr),
+        format!("DIV {} SHIFT {} ({}HZ)", ch.divisor_code, ch.shift_clock_freq, hz),
+        format!("ENV VOL {} {} P:{}", ch.envelope.current_vol, if ch.envelope.sweep_increase {"UP"} else {"DOWN"}, ch.envelope.period),
+        format!("LEN {} {}", on_off(ch.length_counter.enabled), ch.length_counter.counter),
+    ]
+}
+
+// 3x5 pixel glyphs, 3 bits per row from the top, msb on the left
+fn glyph(c: char) -> u16 {
+    let rows: [u8; 5] = match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        _ => [0; 5],
+    };
+    rows.iter().fold(0, |acc, row| (acc << 3) | *row as u16)
+}
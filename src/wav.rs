@@ -0,0 +1,104 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::apu::Apu;
+
+// 32-bit IEEE float PCM, the same format that is fed to the SDL queue
+#[derive(Debug)]
+pub struct WavWriter {
+    file: BufWriter<File>,
+    channels: u16,
+    sample_rate: u32,
+    data_len: u32,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let mut writer = WavWriter{
+            file: BufWriter::new(File::create(path)?),
+            channels,
+            sample_rate,
+            data_len: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let sample_rate = self.sample_rate;
+        let block_align = self.channels * 4;
+        let f = &mut self.file;
+        f.write_all(b"RIFF")?;
+        f.write_all(&(36 + self.data_len).to_le_bytes())?;
+        f.write_all(b"WAVE")?;
+        f.write_all(b"fmt ")?;
+        f.write_all(&16u32.to_le_bytes())?;
+        f.write_all(&3u16.to_le_bytes())?; // WAVE_FORMAT_IEEE_FLOAT
+        f.write_all(&self.channels.to_le_bytes())?;
+        f.write_all(&sample_rate.to_le_bytes())?;
+        f.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        f.write_all(&block_align.to_le_bytes())?;
+        f.write_all(&32u16.to_le_bytes())?;
+        f.write_all(b"data")?;
+        f.write_all(&self.data_len.to_le_bytes())?;
+        Ok(())
+    }
+
+    // The header is patched after every write so the file stays valid
+    // even if the process exits without finishing the recording.
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len += 4 * samples.len() as u32;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+#[derive(Debug)]
+pub struct Recorder {
+    pub mix: WavWriter,
+    pub stems: Option<[WavWriter; 4]>,
+}
+
+impl Recorder {
+    pub fn new(path: &str, stems: bool, sample_rate: u32) -> io::Result<Self> {
+        let stems = if stems {
+            Some([
+                WavWriter::create(stem_path(path, 1), 1, sample_rate)?,
+                WavWriter::create(stem_path(path, 2), 1, sample_rate)?,
+                WavWriter::create(stem_path(path, 3), 1, sample_rate)?,
+                WavWriter::create(stem_path(path, 4), 1, sample_rate)?,
+            ])
+        }else {
+            None
+        };
+        Ok(Recorder{
+            mix: WavWriter::create(path, 2, sample_rate)?,
+            stems,
+        })
+    }
+
+    // Writes the stereo samples taken from apu.buffer and the per channel stem buffers,
+    // the caller clears the stems once every consumer has seen them
+    pub fn record(&mut self, apu: &Apu, mix: &[f32]) -> io::Result<()> {
+        self.mix.write(mix)?;
+        if let Some(stems) = &mut self.stems {
+            for (writer, buffer) in stems.iter_mut().zip(apu.stems.iter()) {
+                writer.write(buffer)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// "music.wav" => "music_ch1.wav"
+pub fn stem_path(path: &str, ch: u8) -> PathBuf {
+    let path = Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}_ch{}.wav", stem, ch))
+}